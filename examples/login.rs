@@ -0,0 +1,16 @@
+use spotify_oauth::{login, FileTokenStore, LoginStrategy, SpotifyAuth, SpotifyScope};
+use std::error::Error;
+
+#[async_std::main]
+async fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+    // Setup Spotify Auth and the file the token is saved into.
+    let auth = SpotifyAuth::new_from_env("code".into(), vec![SpotifyScope::Streaming], false);
+    let store = FileTokenStore::new("token.json");
+
+    // Capture the callback on the redirect URI and save the token.
+    let token = login(&auth, LoginStrategy::Loopback, Some(&store)).await?;
+
+    println!("Token: {:#?}", token);
+
+    Ok(())
+}
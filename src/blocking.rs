@@ -0,0 +1,254 @@
+//! Blocking equivalents of the token requests.
+//!
+//! These functions make the same requests as their asynchronous counterparts without needing an
+//! async runtime, and are enabled with the ``blocking`` feature. Requests are made with the
+//! [`UreqClient`] unless a [`BlockingHttpClient`] is given explicitly, and are retried with the
+//! default [`RetryPolicy`].
+//!
+//! # Example
+//!
+//! ```no_run
+//! use std::{io::stdin, str::FromStr, error::Error};
+//! use spotify_oauth::{blocking, SpotifyAuth, SpotifyCallback, SpotifyScope};
+//!
+//! fn main() -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+//!     let auth = SpotifyAuth::new_from_env("code".into(), vec![SpotifyScope::Streaming], false);
+//!     println!("Open: {}", auth.authorize_url()?);
+//!
+//!     println!("Input callback URL:");
+//!     let mut buffer = String::new();
+//!     stdin().read_line(&mut buffer)?;
+//!
+//!     // Convert the given callback URL into a token without an async runtime.
+//!     let callback = SpotifyCallback::from_str(buffer.trim())?;
+//!     let token = blocking::convert_into_token(callback, auth.client_id, auth.client_secret, auth.redirect_uri)?;
+//!
+//!     Ok(())
+//! }
+//! ```
+
+use url::Url;
+
+use crate::error::*;
+use crate::http::{BlockingHttpClient, UreqClient};
+use crate::{
+    Grant, RetryPolicy, SecretString, SpotifyCallback, SpotifyToken, SystemClock, SPOTIFY_TOKEN_URL,
+};
+
+/// Converts the Spotify Callback object into a Spotify Token object.
+///
+/// Blocking equivalent of [`SpotifyCallback::convert_into_token`].
+pub fn convert_into_token(
+    callback: SpotifyCallback,
+    client_id: String,
+    client_secret: SecretString,
+    redirect_uri: Url,
+) -> SpotifyResult<SpotifyToken> {
+    convert_into_token_with(
+        &UreqClient::default(),
+        callback,
+        client_id,
+        client_secret,
+        redirect_uri,
+    )
+}
+
+/// Converts the Spotify Callback object into a Spotify Token object using the given HTTP client.
+///
+/// Blocking equivalent of [`SpotifyCallback::convert_into_token_with`].
+pub fn convert_into_token_with<C: BlockingHttpClient + ?Sized>(
+    http: &C,
+    callback: SpotifyCallback,
+    client_id: String,
+    client_secret: SecretString,
+    redirect_uri: Url,
+) -> SpotifyResult<SpotifyToken> {
+    Grant::AuthorizationCode {
+        code: callback.authorization_code()?,
+        redirect_uri: &redirect_uri,
+        code_verifier: None,
+    }
+    .execute_blocking(
+        http,
+        SPOTIFY_TOKEN_URL,
+        &client_id,
+        client_secret.expose_secret(),
+        &RetryPolicy::default(),
+        &SystemClock,
+    )
+}
+
+/// Request a new access token using the refresh token of the given token.
+///
+/// Blocking equivalent of [`SpotifyToken::refresh`].
+pub fn refresh(
+    token: &SpotifyToken,
+    client_id: &str,
+    client_secret: &str,
+) -> SpotifyResult<SpotifyToken> {
+    refresh_with(&UreqClient::default(), token, client_id, client_secret)
+}
+
+/// Request a new access token using the refresh token of the given token and the given HTTP client.
+///
+/// Blocking equivalent of [`SpotifyToken::refresh_with`].
+pub fn refresh_with<C: BlockingHttpClient + ?Sized>(
+    http: &C,
+    token: &SpotifyToken,
+    client_id: &str,
+    client_secret: &str,
+) -> SpotifyResult<SpotifyToken> {
+    token.verify_client(client_id)?;
+
+    Grant::RefreshToken {
+        refresh_token: token.refresh_token.expose_secret(),
+    }
+    .execute_blocking(
+        http,
+        SPOTIFY_TOKEN_URL,
+        client_id,
+        client_secret,
+        &RetryPolicy::default(),
+        &SystemClock,
+    )
+}
+
+/// Request a token using only a stored refresh token.
+///
+/// Blocking equivalent of [`SpotifyToken::from_refresh_token`].
+pub fn from_refresh_token(
+    client_id: &str,
+    client_secret: &str,
+    refresh_token: &str,
+) -> SpotifyResult<SpotifyToken> {
+    from_refresh_token_with(
+        &UreqClient::default(),
+        client_id,
+        client_secret,
+        refresh_token,
+    )
+}
+
+/// Request a token using only a stored refresh token and the given HTTP client.
+///
+/// Blocking equivalent of [`SpotifyToken::from_refresh_token_with`].
+pub fn from_refresh_token_with<C: BlockingHttpClient + ?Sized>(
+    http: &C,
+    client_id: &str,
+    client_secret: &str,
+    refresh_token: &str,
+) -> SpotifyResult<SpotifyToken> {
+    Grant::RefreshToken { refresh_token }.execute_blocking(
+        http,
+        SPOTIFY_TOKEN_URL,
+        client_id,
+        client_secret,
+        &RetryPolicy::default(),
+        &SystemClock,
+    )
+}
+
+/// Request a token using the client credentials and refresh token in the environment.
+///
+/// Blocking equivalent of [`SpotifyToken::from_env`].
+pub fn from_env() -> SpotifyResult<SpotifyToken> {
+    let (client_id, client_secret, refresh_token) = crate::refresh_credentials_from_env()?;
+
+    from_refresh_token(
+        &client_id,
+        client_secret.expose_secret(),
+        refresh_token.expose_secret(),
+    )
+}
+
+/// Request an access token for the application itself using the client credentials flow.
+///
+/// Blocking equivalent of [`SpotifyToken::client_credentials`].
+pub fn client_credentials(client_id: &str, client_secret: &str) -> SpotifyResult<SpotifyToken> {
+    client_credentials_with(&UreqClient::default(), client_id, client_secret)
+}
+
+/// Request an access token for the application itself using the given HTTP client.
+///
+/// Blocking equivalent of [`SpotifyToken::client_credentials_with`].
+pub fn client_credentials_with<C: BlockingHttpClient + ?Sized>(
+    http: &C,
+    client_id: &str,
+    client_secret: &str,
+) -> SpotifyResult<SpotifyToken> {
+    Grant::ClientCredentials.execute_blocking(
+        http,
+        SPOTIFY_TOKEN_URL,
+        client_id,
+        client_secret,
+        &RetryPolicy::default(),
+        &SystemClock,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HttpResponse, ScriptedHttpClient};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_blocking_convert_into_token() {
+        let http = ScriptedHttpClient::new();
+        http.push_response(HttpResponse::new(
+            200,
+            r#"{"access_token":"NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw","token_type":"Bearer","scope":"streaming","expires_in":3600,"refresh_token":"NgAagAHfVxDkSvCUm_SHo"}"#,
+        ));
+
+        let callback =
+            SpotifyCallback::from_str("http://localhost:8888/callback?code=AQD0yXvFEOvw&state=sN")
+                .unwrap();
+        let token = convert_into_token_with(
+            &http,
+            callback,
+            "id".into(),
+            "secret".into(),
+            Url::parse("http://localhost:8888/callback").unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            token.access_token.expose_secret(),
+            "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw"
+        );
+        assert_eq!(http.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_blocking_refresh() {
+        let http = ScriptedHttpClient::new();
+        http.push_response(HttpResponse::new(
+            200,
+            r#"{"access_token":"NgA6ZcYIixn8bU","token_type":"Bearer","scope":"streaming","expires_in":3600}"#,
+        ));
+        http.push_response(HttpResponse::new(400, r#"{"error":"invalid_grant"}"#));
+
+        let token = SpotifyToken {
+            access_token: "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw".into(),
+            token_type: "Bearer".to_string(),
+            scope: vec![],
+            expires_in: 3600,
+            expires_at: None,
+            issued_at: None,
+            refresh_token: "NgAagAHfVxDkSvCUm_SHo".into(),
+            client_id: None,
+        };
+
+        let refreshed = refresh_with(&http, &token, "id", "secret").unwrap();
+        assert_eq!(refreshed.access_token.expose_secret(), "NgA6ZcYIixn8bU");
+        assert_eq!(refreshed.client_id.as_deref(), Some("id"));
+        assert_eq!(
+            refreshed.refresh_token.expose_secret(),
+            "NgAagAHfVxDkSvCUm_SHo"
+        );
+
+        let error = refresh_with(&http, &refreshed, "id", "secret").unwrap_err();
+        assert!(error.requires_reauthorization());
+        assert_eq!(error.status(), Some(400));
+    }
+}
@@ -0,0 +1,631 @@
+//! Long-lived client for the Spotify Accounts service.
+
+use sha2::{Digest, Sha256};
+#[cfg(any(feature = "surf-client", feature = "reqwest-client"))]
+use snafu::ResultExt;
+use url::Url;
+
+#[cfg(any(feature = "surf-client", feature = "reqwest-client"))]
+use std::env;
+use std::sync::Arc;
+
+use crate::clock::{Clock, SystemClock};
+#[cfg(any(feature = "surf-client", feature = "reqwest-client"))]
+use crate::config::HttpConfig;
+use crate::error::*;
+use crate::grant::Grant;
+#[cfg(any(feature = "surf-client", feature = "reqwest-client"))]
+use crate::http::DefaultHttpClient;
+use crate::http::HttpClient;
+use crate::pending::{PendingAuthorization, PendingAuthorizationStore};
+use crate::replay::{Issued, ReplayGuard};
+use crate::retry::RetryPolicy;
+use crate::secret::SecretString;
+use crate::signed_state::is_local_path;
+use crate::state::StateGenerator;
+use crate::{SpotifyCallback, SpotifyScope, SpotifyToken, SPOTIFY_AUTH_URL, SPOTIFY_TOKEN_URL};
+
+/// Spotify OAuth Client
+///
+/// Owns the application credentials, the endpoint URLs, the HTTP client and the retry policy, so
+/// a single client can be shared by every login handled by a server. Timeouts and the connection
+/// pool belong to the HTTP client, which can be built from a [`HttpConfig`](crate::HttpConfig).
+///
+/// # Example
+///
+/// ```no_run
+/// # use spotify_oauth::{SpotifyCallback, SpotifyOAuthClient, SpotifyScope};
+/// # use std::str::FromStr;
+/// # #[async_std::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+/// let client = SpotifyOAuthClient::from_env()?;
+///
+/// // Send the user to the authorization URL.
+/// let state = client.generate_state();
+/// let url = client.authorize_url(&state, &[SpotifyScope::Streaming], false);
+///
+/// // Exchange the code given in the callback for a token.
+/// let callback = SpotifyCallback::from_str("https://example.com/callback?code=NApCCgBkWtQ&state=test")?;
+/// callback.validate_state(&state)?;
+/// let token = client.exchange_code(&callback).await?;
+/// # Ok(()) }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SpotifyOAuthClient<C> {
+    http: C,
+    client_id: String,
+    client_secret: SecretString,
+    redirect_uri: Url,
+    authorize_endpoint: Url,
+    token_endpoint: Url,
+    retry: RetryPolicy,
+    state: StateGenerator,
+    replay: Option<Arc<ReplayGuard>>,
+    clock: Arc<dyn Clock>,
+}
+
+#[cfg(any(feature = "surf-client", feature = "reqwest-client"))]
+impl SpotifyOAuthClient<DefaultHttpClient> {
+    /// Create a new client using the [`DefaultHttpClient`] built from the given configuration.
+    pub fn from_config(
+        client_id: String,
+        client_secret: SecretString,
+        redirect_uri: Url,
+        config: &HttpConfig,
+    ) -> SpotifyResult<Self> {
+        let http = DefaultHttpClient::from_config(config)?;
+        Ok(Self::new(http, client_id, client_secret, redirect_uri))
+    }
+
+    /// Create a new client from values in the environment.
+    ///
+    /// This function loads ``SPOTIFY_CLIENT_ID``, ``SPOTIFY_CLIENT_SECRET`` and ``SPOTIFY_REDIRECT_URI``
+    /// from the environment and the ``.env`` file, and uses [`HttpConfig::default`].
+    pub fn from_env() -> SpotifyResult<Self> {
+        // Load local .env file.
+        dotenv::dotenv().ok();
+
+        Self::from_config(
+            env::var("SPOTIFY_CLIENT_ID").context(EnvError)?,
+            env::var("SPOTIFY_CLIENT_SECRET").context(EnvError)?.into(),
+            Url::parse(&env::var("SPOTIFY_REDIRECT_URI").context(EnvError)?).context(UrlError)?,
+            &HttpConfig::default(),
+        )
+    }
+}
+
+impl<C: HttpClient> SpotifyOAuthClient<C> {
+    /// Create a new client with the given HTTP client and application credentials.
+    pub fn new(http: C, client_id: String, client_secret: SecretString, redirect_uri: Url) -> Self {
+        Self {
+            http,
+            client_id,
+            client_secret,
+            redirect_uri,
+            authorize_endpoint: Url::parse(SPOTIFY_AUTH_URL).unwrap(),
+            token_endpoint: Url::parse(SPOTIFY_TOKEN_URL).unwrap(),
+            retry: RetryPolicy::default(),
+            state: StateGenerator::default(),
+            replay: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Send users to the given authorization endpoint instead of the Spotify Accounts service.
+    pub fn with_authorize_endpoint(mut self, authorize_endpoint: Url) -> Self {
+        self.authorize_endpoint = authorize_endpoint;
+        self
+    }
+
+    /// Request tokens from the given token endpoint instead of the Spotify Accounts service.
+    pub fn with_token_endpoint(mut self, token_endpoint: Url) -> Self {
+        self.token_endpoint = token_endpoint;
+        self
+    }
+
+    /// Retry failed token requests according to the given policy, defaults to [`RetryPolicy::default`].
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Generate states with the given generator, defaults to [`StateGenerator::default`].
+    pub fn with_state_generator(mut self, state: StateGenerator) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// Reject callbacks whose code or state was already used with the given guard.
+    ///
+    /// Clones of the client share the guard.
+    pub fn with_replay_guard(mut self, replay: ReplayGuard) -> Self {
+        self.replay = Some(Arc::new(replay));
+        self
+    }
+
+    /// Compute when issued tokens expire with the given clock, defaults to [`SystemClock`].
+    pub fn with_clock<K: Clock + 'static>(mut self, clock: K) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Generate a new state for an authorization request.
+    pub fn generate_state(&self) -> String {
+        self.state.generate()
+    }
+
+    /// The Spotify Application Client ID.
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    /// The URI Spotify redirects to after the user grants or denies permission.
+    pub fn redirect_uri(&self) -> &Url {
+        &self.redirect_uri
+    }
+
+    /// The HTTP client used for token requests.
+    pub fn http(&self) -> &C {
+        &self.http
+    }
+
+    /// The authorization URL for the given state and scopes.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use spotify_oauth::{ScriptedHttpClient, SpotifyOAuthClient, SpotifyScope};
+    /// let client = SpotifyOAuthClient::new(ScriptedHttpClient::new(), "00000000000".into(), "secret".into(), "http://localhost:8000/callback".parse().unwrap());
+    /// let url = client.authorize_url("sN", &[SpotifyScope::Streaming], false);
+    /// # assert_eq!(url, "https://accounts.spotify.com/authorize?client_id=00000000000&response_type=code&redirect_uri=http%3A%2F%2Flocalhost%3A8000%2Fcallback&state=sN&scope=streaming&show_dialog=false");
+    /// ```
+    pub fn authorize_url(&self, state: &str, scope: &[SpotifyScope], show_dialog: bool) -> String {
+        self.authorize_url_with(state, scope, show_dialog, None)
+    }
+
+    /// The authorization URL, with the PKCE code challenge of the given verifier if any.
+    fn authorize_url_with(
+        &self,
+        state: &str,
+        scope: &[SpotifyScope],
+        show_dialog: bool,
+        code_verifier: Option<&str>,
+    ) -> String {
+        let scope = scope
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        let mut url = self.authorize_endpoint.clone();
+        url.query_pairs_mut()
+            .append_pair("client_id", &self.client_id)
+            .append_pair("response_type", "code")
+            .append_pair("redirect_uri", self.redirect_uri.as_str())
+            .append_pair("state", state)
+            .append_pair("scope", &scope)
+            .append_pair("show_dialog", &show_dialog.to_string());
+
+        if let Some(code_verifier) = code_verifier {
+            url.query_pairs_mut()
+                .append_pair("code_challenge", &code_challenge(code_verifier))
+                .append_pair("code_challenge_method", "S256");
+        }
+
+        url.to_string()
+    }
+
+    /// Exchange the authorization code of the callback for a token.
+    ///
+    /// The state of the callback must be validated by the caller beforehand. With a
+    /// [`ReplayGuard`], callbacks which were already used fail with ``code_replayed``.
+    pub async fn exchange_code(&self, callback: &SpotifyCallback) -> SpotifyResult<SpotifyToken> {
+        let code = callback.authorization_code()?;
+
+        if let Some(ref replay) = self.replay {
+            if let Some(issued) = replay.consume(code, callback.state())? {
+                return Ok(issued.token);
+            }
+        }
+
+        let token = self.exchange(code, &self.redirect_uri, None).await;
+        if let Some(ref replay) = self.replay {
+            replay.complete(code, None, &token);
+        }
+
+        token
+    }
+
+    /// Start an authorization request and return the URL to send the user to.
+    ///
+    /// The request is kept in the given store under a new state until its callback arrives, along
+    /// with the local path to return the user to afterwards. Every request gets its own PKCE code
+    /// verifier, whose ``S256`` challenge is sent with the authorization request and which is sent
+    /// with the code exchange.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use spotify_oauth::{MemoryPendingStore, SpotifyCallback, SpotifyOAuthClient, SpotifyScope};
+    /// # use std::str::FromStr;
+    /// # #[async_std::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    /// let client = SpotifyOAuthClient::from_env()?;
+    /// let pending = MemoryPendingStore::new();
+    ///
+    /// // Send the user to the authorization URL.
+    /// let url = client.start_authorization(&pending, &[SpotifyScope::Streaming], false, Some("/playlists"))?;
+    ///
+    /// // Exchange the code with the parameters of the matching request.
+    /// let callback = SpotifyCallback::from_str("https://example.com/callback?code=NApCCgBkWtQ&state=test")?;
+    /// let (request, token) = client.complete_authorization(&pending, &callback).await?;
+    /// # Ok(()) }
+    /// ```
+    pub fn start_authorization(
+        &self,
+        pending: &dyn PendingAuthorizationStore,
+        scope: &[SpotifyScope],
+        show_dialog: bool,
+        return_path: Option<&str>,
+    ) -> SpotifyResult<String> {
+        // Verifiers need at least 43 characters, which 32 random bytes encode to.
+        let code_verifier = self.state.clone().with_entropy(32).generate();
+        let mut request = PendingAuthorization::new(
+            self.generate_state(),
+            self.redirect_uri.clone(),
+            scope.to_vec(),
+            &*self.clock,
+        )
+        .with_code_verifier(code_verifier.as_str().into());
+
+        if let Some(return_path) = return_path {
+            if !is_local_path(return_path) {
+                return InvalidState {
+                    context: "Return path is not a local path.",
+                }
+                .fail();
+            }
+
+            request = request.with_return_path(return_path.to_string());
+        }
+
+        let url = self.authorize_url_with(&request.state, scope, show_dialog, Some(&code_verifier));
+        pending.insert(request)?;
+
+        Ok(url)
+    }
+
+    /// Complete the authorization request matching the state of the callback.
+    ///
+    /// The request is taken from the store, so each callback is only completed once, and the code
+    /// is exchanged with its redirect URI and PKCE code verifier. Callbacks without a matching
+    /// request fail with ``invalid_state``. Callbacks of denied or failed authorizations fail
+    /// without taking the request.
+    pub async fn complete_authorization(
+        &self,
+        pending: &dyn PendingAuthorizationStore,
+        callback: &SpotifyCallback,
+    ) -> SpotifyResult<(PendingAuthorization, SpotifyToken)> {
+        // Denied or failed authorizations leave the request for another attempt.
+        let code = callback.authorization_code()?;
+
+        // Check for replays before taking the request, which was taken by the first callback.
+        let replay = match self.replay {
+            Some(ref replay) => match replay.consume(code, callback.state())? {
+                Some(Issued {
+                    request: Some(request),
+                    token,
+                }) => return Ok((request, token)),
+                Some(_) => {
+                    return CodeReplayed {
+                        context: "The authorization code was already used.",
+                    }
+                    .fail()
+                }
+                None => Some(replay),
+            },
+            None => None,
+        };
+
+        let request = match pending.take(callback.state()) {
+            Ok(Some(x)) => x,
+            result => {
+                // Let a later callback with the same code try again.
+                if let Some(replay) = replay {
+                    replay.release(code);
+                }
+
+                result?;
+                return InvalidState {
+                    context: "No pending authorization request matches the state.",
+                }
+                .fail();
+            }
+        };
+
+        let verifier = request.code_verifier.as_ref().map(|x| x.expose_secret());
+        let token = self.exchange(code, &request.redirect_uri, verifier).await;
+        if let Some(replay) = replay {
+            replay.complete(code, Some(&request), &token);
+        }
+
+        Ok((request, token?))
+    }
+
+    /// Exchange the authorization code for a token with the given parameters.
+    async fn exchange(
+        &self,
+        code: &str,
+        redirect_uri: &Url,
+        code_verifier: Option<&str>,
+    ) -> SpotifyResult<SpotifyToken> {
+        Grant::AuthorizationCode {
+            code,
+            redirect_uri,
+            code_verifier,
+        }
+        .execute(
+            &self.http,
+            self.token_endpoint.as_str(),
+            &self.client_id,
+            self.client_secret.expose_secret(),
+            &self.retry,
+            &*self.clock,
+        )
+        .await
+    }
+
+    /// Request a new access token using the refresh token of the given token.
+    ///
+    /// If Spotify does not rotate the refresh token, the current one is kept in the new token.
+    pub async fn refresh(&self, token: &SpotifyToken) -> SpotifyResult<SpotifyToken> {
+        token.verify_client(&self.client_id)?;
+
+        Grant::RefreshToken {
+            refresh_token: token.refresh_token.expose_secret(),
+        }
+        .execute(
+            &self.http,
+            self.token_endpoint.as_str(),
+            &self.client_id,
+            self.client_secret.expose_secret(),
+            &self.retry,
+            &*self.clock,
+        )
+        .await
+    }
+
+    /// Request an access token for the application itself using the client credentials flow.
+    pub async fn client_credentials(&self) -> SpotifyResult<SpotifyToken> {
+        Grant::ClientCredentials
+            .execute(
+                &self.http,
+                self.token_endpoint.as_str(),
+                &self.client_id,
+                self.client_secret.expose_secret(),
+                &self.retry,
+                &*self.clock,
+            )
+            .await
+    }
+}
+
+/// The PKCE ``S256`` code challenge of the given code verifier.
+fn code_challenge(code_verifier: &str) -> String {
+    base64::encode_config(
+        &Sha256::digest(code_verifier.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HttpResponse, MemoryPendingStore, ScriptedHttpClient};
+    use futures::executor::block_on;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    fn client() -> SpotifyOAuthClient<ScriptedHttpClient> {
+        SpotifyOAuthClient::new(
+            ScriptedHttpClient::new(),
+            "id".into(),
+            "secret".into(),
+            Url::parse("http://localhost:8888/callback").unwrap(),
+        )
+        .with_token_endpoint(Url::parse("http://127.0.0.1:9000/api/token").unwrap())
+        .with_retry_policy(RetryPolicy::none())
+    }
+
+    #[test]
+    fn test_exchange_code_and_refresh() {
+        let client = client();
+        client.http().push_response(HttpResponse::new(
+            200,
+            r#"{"access_token":"NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw","token_type":"Bearer","scope":"streaming","expires_in":3600,"refresh_token":"NgAagAHfVxDkSvCUm_SHo"}"#,
+        ));
+        client.http().push_response(HttpResponse::new(
+            200,
+            r#"{"access_token":"NgA6ZcYIixn8bU","token_type":"Bearer","expires_in":3600}"#,
+        ));
+
+        let callback =
+            SpotifyCallback::from_str("http://localhost:8888/callback?code=AQD0yXvFEOvw&state=sN")
+                .unwrap();
+        let mut token = block_on(client.exchange_code(&callback)).unwrap();
+        let refreshed = block_on(client.refresh(&token)).unwrap();
+        assert_eq!(token.client_id.as_deref(), Some("id"));
+        assert_eq!(refreshed.client_id.as_deref(), Some("id"));
+
+        assert_eq!(refreshed.access_token.expose_secret(), "NgA6ZcYIixn8bU");
+        assert_eq!(
+            refreshed.refresh_token.expose_secret(),
+            "NgAagAHfVxDkSvCUm_SHo"
+        );
+
+        // Tokens of another client are rejected without a request.
+        token.client_id = Some("staging".into());
+        assert_eq!(
+            block_on(client.refresh(&token)).unwrap_err().code(),
+            "client_mismatch"
+        );
+
+        let requests = client.http().requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests
+            .iter()
+            .all(|x| x.url == "http://127.0.0.1:9000/api/token"));
+        assert!(requests[0]
+            .body
+            .starts_with(b"grant_type=authorization_code&code=AQD0yXvFEOvw"));
+    }
+
+    #[test]
+    fn test_complete_authorization() {
+        let client = client();
+        let pending = MemoryPendingStore::new();
+        client.http().push_response(HttpResponse::new(
+            200,
+            r#"{"access_token":"NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw","token_type":"Bearer","scope":"streaming","expires_in":3600,"refresh_token":"NgAagAHfVxDkSvCUm_SHo"}"#,
+        ));
+
+        let url = client
+            .start_authorization(&pending, &[SpotifyScope::Streaming], false, Some("/home"))
+            .unwrap();
+        let query = |name: &str| {
+            Url::parse(&url)
+                .unwrap()
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .unwrap()
+                .1
+                .into_owned()
+        };
+        let state = query("state");
+        assert_eq!(query("code_challenge_method"), "S256");
+
+        // A denied authorization leaves the request for another attempt.
+        let denied = SpotifyCallback::new(None, Some("access_denied".into()), state.clone());
+        assert!(block_on(client.complete_authorization(&pending, &denied)).is_err());
+        assert!(!pending.is_empty());
+
+        let callback = SpotifyCallback::new(Some("AQD0yXvFEOvw".into()), None, state);
+        let (request, token) =
+            block_on(client.complete_authorization(&pending, &callback)).unwrap();
+        assert_eq!(request.return_path.as_deref(), Some("/home"));
+
+        // The verifier kept with the request matches the challenge and is sent with the exchange.
+        let verifier = request.code_verifier.as_ref().unwrap().expose_secret();
+        assert_eq!(verifier.len(), 43);
+        assert_eq!(code_challenge(verifier), query("code_challenge"));
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+        assert!(client.http().requests()[0]
+            .body
+            .ends_with(format!("&code_verifier={}", verifier).as_bytes()));
+        assert_eq!(
+            token.access_token.expose_secret(),
+            "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw"
+        );
+
+        // The request was consumed by the first callback.
+        let error = block_on(client.complete_authorization(&pending, &callback)).unwrap_err();
+        assert_eq!(error.code(), "invalid_state");
+        assert_eq!(client.http().requests().len(), 1);
+
+        assert!(client
+            .start_authorization(&pending, &[], false, Some("//example.com"))
+            .is_err());
+        assert!(pending.is_empty());
+    }
+
+    /// A pending store which can't be reached.
+    struct UnavailableStore;
+
+    impl PendingAuthorizationStore for UnavailableStore {
+        fn insert(&self, _: PendingAuthorization) -> SpotifyResult<()> {
+            Ok(())
+        }
+
+        fn take(&self, _: &str) -> SpotifyResult<Option<PendingAuthorization>> {
+            Err(SpotifyError::IoError {
+                source: std::io::Error::other("unavailable"),
+            })
+        }
+    }
+
+    #[test]
+    fn test_replay_guard() {
+        let client = client().with_replay_guard(ReplayGuard::new());
+        client.http().push_response(HttpResponse::new(
+            200,
+            r#"{"access_token":"NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw","token_type":"Bearer","scope":"streaming","expires_in":3600,"refresh_token":"NgAagAHfVxDkSvCUm_SHo"}"#,
+        ));
+
+        let callback = SpotifyCallback::new(Some("AQD0yXvFEOvw".into()), None, "sN".into());
+        block_on(client.exchange_code(&callback)).unwrap();
+
+        // The replay is rejected without calling the token endpoint.
+        let error = block_on(client.exchange_code(&callback)).unwrap_err();
+        assert_eq!(error.code(), "code_replayed");
+        assert_eq!(client.http().requests().len(), 1);
+
+        // A code whose request could not be loaded is released for the next callback.
+        let released = SpotifyCallback::new(Some("AQBm4n8Ud".into()), None, "x7".into());
+        let error = block_on(client.complete_authorization(&UnavailableStore, &released));
+        assert_eq!(error.unwrap_err().code(), "io_error");
+        let error = block_on(client.complete_authorization(&MemoryPendingStore::new(), &released));
+        assert_eq!(error.unwrap_err().code(), "invalid_state");
+        assert_eq!(client.http().requests().len(), 1);
+
+        // A browser refresh within the reissue window gets the same token.
+        let pending = MemoryPendingStore::new();
+        let client = client
+            .with_replay_guard(ReplayGuard::new().with_reissue_window(Duration::from_secs(60)));
+        client.http().push_response(HttpResponse::new(
+            200,
+            r#"{"access_token":"NgA6ZcYIixn8bU","token_type":"Bearer","expires_in":3600}"#,
+        ));
+        pending
+            .insert(PendingAuthorization::new(
+                "sN".into(),
+                client.redirect_uri().clone(),
+                vec![],
+                &SystemClock,
+            ))
+            .unwrap();
+
+        let (_, token) = block_on(client.complete_authorization(&pending, &callback)).unwrap();
+        let (_, again) = block_on(client.complete_authorization(&pending, &callback)).unwrap();
+        assert_eq!(again, token);
+        assert_eq!(client.http().requests().len(), 2);
+    }
+
+    #[test]
+    fn test_authorize_endpoint() {
+        let client = client()
+            .with_authorize_endpoint(Url::parse("http://127.0.0.1:9000/authorize").unwrap());
+
+        assert_eq!(
+            client.authorize_url("sN", &[SpotifyScope::Streaming, SpotifyScope::AppRemoteControl], true),
+            "http://127.0.0.1:9000/authorize?client_id=id&response_type=code&redirect_uri=http%3A%2F%2Flocalhost%3A8888%2Fcallback&state=sN&scope=streaming+app-remote-control&show_dialog=true"
+        );
+    }
+
+    #[test]
+    fn test_authorize_url_snapshot() {
+        let client = client().with_state_generator(
+            StateGenerator::new()
+                .with_entropy(16)
+                .with_rng(rand::rngs::mock::StepRng::new(0, 1)),
+        );
+
+        let state = client.generate_state();
+        assert_eq!(
+            client.authorize_url(&state, &[SpotifyScope::Streaming], false),
+            "https://accounts.spotify.com/authorize?client_id=id&response_type=code&redirect_uri=http%3A%2F%2Flocalhost%3A8888%2Fcallback&state=AAAAAAAAAAABAAAAAAAAAA&scope=streaming&show_dialog=false"
+        );
+    }
+}
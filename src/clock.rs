@@ -0,0 +1,79 @@
+//! Sources of the current time for token expiry.
+
+use chrono::{DateTime, Utc};
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A source of the current time.
+///
+/// Used to compute when tokens expire, to check whether they have expired and to schedule
+/// refreshes. [`SystemClock`] is used by default, [`ManualClock`] makes expiry testable without
+/// sleeping.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// The current time.
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The clock of the operating system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock which only moves when told to, for tests.
+///
+/// Clones share the same time, so a clone kept by the test can advance the clock given to a
+/// [`TokenManager`](crate::TokenManager) or [`SpotifyOAuthClient`](crate::SpotifyOAuthClient).
+///
+/// # Example
+///
+/// ```
+/// # use spotify_oauth::{Clock, ManualClock};
+/// # use std::time::Duration;
+/// let clock = ManualClock::new(chrono::Utc::now());
+/// let start = clock.now();
+///
+/// clock.advance(Duration::from_secs(3600));
+/// assert_eq!((clock.now() - start).num_seconds(), 3600);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl ManualClock {
+    /// Create a new clock stopped at the given time.
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    /// Move the clock forward by the given duration.
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap_or_else(|e| e.into_inner());
+        *now += chrono::Duration::from_std(duration).unwrap_or_else(|_| chrono::Duration::zero());
+    }
+
+    /// Set the clock to the given time.
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner()) = now;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The unix timestamp the given number of seconds after the current time of the clock.
+pub(crate) fn timestamp_after(clock: &dyn Clock, elapsed: u32) -> i64 {
+    clock.now().timestamp() + i64::from(elapsed)
+}
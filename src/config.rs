@@ -0,0 +1,123 @@
+//! Configuration of the HTTP clients used for calls to the Spotify Accounts service.
+
+use url::Url;
+
+use std::env;
+use std::time::Duration;
+
+/// The User-Agent sent when none is configured.
+pub const DEFAULT_USER_AGENT: &str = concat!("spotify-oauth/", env!("CARGO_PKG_VERSION"));
+
+/// The host every token request is sent to, used to match ``NO_PROXY``.
+const SPOTIFY_ACCOUNTS_HOST: &str = "accounts.spotify.com";
+
+/// HTTP Client Configuration
+///
+/// Every HTTP client adapter can be built from this configuration using its ``from_config``
+/// function, so token requests behave the same whichever HTTP library is used.
+///
+/// # Example
+///
+/// ```
+/// # use spotify_oauth::HttpConfig;
+/// # use std::time::Duration;
+/// // Use the proxy from the environment and give up on requests after 10 seconds.
+/// let config = HttpConfig {
+///     timeout: Some(Duration::from_secs(10)),
+///     ..HttpConfig::from_env()
+/// };
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpConfig {
+    /// The maximum time to wait for a connection to be established.
+    pub connect_timeout: Option<Duration>,
+    /// The maximum time a whole request may take, from connecting until the body has been read.
+    pub timeout: Option<Duration>,
+    /// The proxy every request is sent through.
+    pub proxy: Option<Url>,
+    /// PEM encoded certificates trusted in addition to the built-in root certificates.
+    pub root_certificates: Vec<Vec<u8>>,
+    /// The User-Agent header sent with every request.
+    pub user_agent: String,
+    /// The maximum size of a response body in bytes, larger responses are rejected.
+    pub max_response_size: usize,
+}
+
+/// Implementation of Default for HttpConfig.
+///
+/// Connecting times out after 10 seconds and whole requests after 30 seconds. No proxy or extra
+/// root certificates are used, the User-Agent is [`DEFAULT_USER_AGENT`] and response bodies are
+/// limited to 64 KiB.
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Some(Duration::from_secs(10)),
+            timeout: Some(Duration::from_secs(30)),
+            proxy: None,
+            root_certificates: vec![],
+            user_agent: DEFAULT_USER_AGENT.to_owned(),
+            max_response_size: 64 * 1024,
+        }
+    }
+}
+
+impl HttpConfig {
+    /// The default configuration, using the proxy given by the standard environment variables.
+    ///
+    /// The proxy is read from ``HTTPS_PROXY`` or ``ALL_PROXY`` (in upper or lower case), unless
+    /// the Spotify Accounts service is excluded by ``NO_PROXY``.
+    pub fn from_env() -> Self {
+        Self {
+            proxy: proxy_from_vars(|x| env::var(x).ok()),
+            ..Self::default()
+        }
+    }
+}
+
+/// Find the proxy for the Spotify Accounts service using the given variable lookup.
+fn proxy_from_vars<F: Fn(&str) -> Option<String>>(var: F) -> Option<Url> {
+    let no_proxy = var("NO_PROXY")
+        .or_else(|| var("no_proxy"))
+        .unwrap_or_default();
+    let excluded = no_proxy.split(',').map(str::trim).any(|x| {
+        let x = x.trim_start_matches('.');
+        x == "*" || (!x.is_empty() && SPOTIFY_ACCOUNTS_HOST.ends_with(x))
+    });
+
+    if excluded {
+        return None;
+    }
+
+    ["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]
+        .iter()
+        .filter_map(|x| var(x))
+        .find(|x| !x.is_empty())
+        .and_then(|x| Url::parse(&x).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_from_vars() {
+        let proxy = proxy_from_vars(|x| match x {
+            "https_proxy" => Some("http://proxy.internal:3128".to_owned()),
+            "ALL_PROXY" => Some("http://other.internal:3128".to_owned()),
+            _ => None,
+        });
+        assert_eq!(
+            proxy,
+            Some(Url::parse("http://proxy.internal:3128").unwrap())
+        );
+
+        let proxy = proxy_from_vars(|x| match x {
+            "HTTPS_PROXY" => Some("http://proxy.internal:3128".to_owned()),
+            "NO_PROXY" => Some("localhost, .spotify.com".to_owned()),
+            _ => None,
+        });
+        assert_eq!(proxy, None);
+
+        assert_eq!(proxy_from_vars(|_| None), None);
+    }
+}
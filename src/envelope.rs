@@ -0,0 +1,192 @@
+//! The versioned format tokens are persisted in.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use snafu::ResultExt;
+
+use crate::clock::Clock;
+use crate::error::*;
+use crate::SpotifyToken;
+
+/// The version of the [`TokenEnvelope`] format written by this version of the crate.
+pub const TOKEN_FORMAT_VERSION: u32 = 1;
+
+/// A persisted token with the metadata needed to keep it usable across versions of the crate.
+///
+/// Version 1 stores the format version, the client ID the token belongs to, when the token was
+/// first stored and last refreshed, and the token itself. Files written by older versions of the
+/// crate contain the bare token, which is migrated with the client ID recorded in the token, if
+/// any, and timestamps estimated from the token. Newer formats are rejected rather than misread.
+///
+/// # Example
+///
+/// ```
+/// # use spotify_oauth::{SystemClock, TokenEnvelope, TOKEN_FORMAT_VERSION};
+/// let envelope = TokenEnvelope::from_json(r#"{"access_token":"NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw","token_type":"Bearer","expires_in":3600,"expires_at":1577840400,"refresh_token":"NgAagAHfVxDkSvCUm_SHo"}"#, &SystemClock).unwrap();
+///
+/// assert_eq!(envelope.version, TOKEN_FORMAT_VERSION);
+/// assert_eq!(envelope.created_at, 1577836800);
+/// assert_eq!(envelope.client_id, None);
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TokenEnvelope {
+    /// The version of the format.
+    pub version: u32,
+    /// The client ID of the application the token was issued to, ``None`` if it is unknown.
+    pub client_id: Option<String>,
+    /// The timestamp at which the token was first stored.
+    pub created_at: i64,
+    /// The timestamp at which the token was last refreshed.
+    pub refreshed_at: i64,
+    /// The token.
+    pub token: SpotifyToken,
+}
+
+impl TokenEnvelope {
+    /// Create a new envelope for a token issued at the current time of the clock.
+    pub fn new(client_id: Option<String>, token: SpotifyToken, clock: &dyn Clock) -> Self {
+        let now = clock.now().timestamp();
+
+        Self {
+            version: TOKEN_FORMAT_VERSION,
+            client_id,
+            created_at: now,
+            refreshed_at: now,
+            token,
+        }
+    }
+
+    /// Replace the token with its refreshed successor, keeping the creation time and client ID.
+    ///
+    /// The refresh time is the current time of the clock.
+    pub fn refreshed(self, token: SpotifyToken, clock: &dyn Clock) -> Self {
+        Self {
+            refreshed_at: clock.now().timestamp(),
+            token,
+            ..self
+        }
+    }
+
+    /// Parse an envelope of any known version, migrating it to the current version.
+    ///
+    /// Bare tokens which record neither when they were issued nor when they expire are dated with
+    /// the current time of the clock.
+    pub fn from_json(json: &str, clock: &dyn Clock) -> SpotifyResult<Self> {
+        let value: Value = serde_json::from_str(json).context(SerdeError)?;
+
+        let version = match value.get("version") {
+            Some(x) => x,
+            None => {
+                return Ok(Self::from_bare(
+                    serde_json::from_value(value).context(SerdeError)?,
+                    clock,
+                ))
+            }
+        };
+
+        match version.as_u64() {
+            Some(x) if x > u64::from(TOKEN_FORMAT_VERSION) => {
+                UnsupportedFormat { version: x }.fail()
+            }
+            Some(1) => serde_json::from_value(value).context(SerdeError),
+            _ => Err(serde::de::Error::custom(format!(
+                "invalid token format version {}",
+                version
+            )))
+            .context(SerdeError),
+        }
+    }
+
+    /// Serialize the envelope in the current version of the format.
+    pub fn to_json(&self) -> SpotifyResult<String> {
+        serde_json::to_string_pretty(self).context(SerdeError)
+    }
+
+    /// Migrate a bare token, as stored before the format was versioned.
+    fn from_bare(token: SpotifyToken, clock: &dyn Clock) -> Self {
+        let issued_at = token
+            .issued_at
+            .or_else(|| token.expires_at.map(|x| x - i64::from(token.expires_in)))
+            .unwrap_or_else(|| clock.now().timestamp());
+
+        Self {
+            version: TOKEN_FORMAT_VERSION,
+            client_id: token.client_id.clone(),
+            created_at: issued_at,
+            refreshed_at: issued_at,
+            token,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualClock, SpotifyScope};
+    use chrono::{TimeZone, Utc};
+
+    fn clock() -> ManualClock {
+        ManualClock::new(Utc.timestamp_opt(1_577_923_200, 0).unwrap())
+    }
+
+    fn token() -> SpotifyToken {
+        SpotifyToken {
+            access_token: "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw".into(),
+            token_type: "Bearer".to_string(),
+            scope: vec![SpotifyScope::Streaming],
+            expires_in: 3600,
+            expires_at: Some(1_577_840_400),
+            issued_at: Some(1_577_836_800),
+            refresh_token: "NgAagAHfVxDkSvCUm_SHo".into(),
+            client_id: None,
+        }
+    }
+
+    #[test]
+    fn test_migrate_bare_token() {
+        // A token as saved by versions before the envelope, with the scopes as variant names.
+        let bare = r#"{
+            "access_token": "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw",
+            "token_type": "Bearer",
+            "scope": ["Streaming"],
+            "expires_in": 3600,
+            "expires_at": 1577840400,
+            "refresh_token": "NgAagAHfVxDkSvCUm_SHo"
+        }"#;
+        let envelope = TokenEnvelope::from_json(bare, &clock()).unwrap();
+        let mut expected = token();
+        expected.issued_at = None;
+
+        assert_eq!(envelope.version, TOKEN_FORMAT_VERSION);
+        assert_eq!(envelope.client_id, None);
+        assert_eq!(envelope.created_at, 1_577_836_800);
+        assert_eq!(envelope.refreshed_at, 1_577_836_800);
+        assert_eq!(envelope.token, expected);
+
+        // Once migrated, the envelope reads back unchanged.
+        let json = envelope.to_json().unwrap();
+        assert_eq!(TokenEnvelope::from_json(&json, &clock()).unwrap(), envelope);
+
+        // Without any timestamp in the token, the time of the clock is used.
+        let bare = r#"{"access_token":"NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw","token_type":"Bearer","expires_in":3600,"refresh_token":"NgAagAHfVxDkSvCUm_SHo"}"#;
+        let envelope = TokenEnvelope::from_json(bare, &clock()).unwrap();
+        assert_eq!(envelope.created_at, 1_577_923_200);
+    }
+
+    #[test]
+    fn test_reject_unknown_versions() {
+        let mut envelope =
+            serde_json::to_value(TokenEnvelope::new(None, token(), &clock())).unwrap();
+        envelope["version"] = 2.into();
+
+        let error = TokenEnvelope::from_json(&envelope.to_string(), &clock()).unwrap_err();
+        assert_eq!(error.code(), "unsupported_format");
+
+        // Malformed versions are not mistaken for newer ones.
+        for version in &[serde_json::json!("1"), 0.into(), (-1).into()] {
+            envelope["version"] = version.clone();
+            let error = TokenEnvelope::from_json(&envelope.to_string(), &clock()).unwrap_err();
+            assert_eq!(error.code(), "invalid_json");
+        }
+    }
+}
@@ -1,13 +1,19 @@
 //! Error Type for the API.
 
 use snafu::Snafu;
-use std::{env, error};
+use std::{env, error, io};
 
 /// Generic Result for the Library
 pub type SpotifyResult<T, E = SpotifyError> = Result<T, E>;
 
+/// Errors returned by the library.
+///
+/// Variants may be added in minor releases and some only exist with a feature enabled, such as
+/// ``DatabaseError`` with ``sqlite``, so matches need a wildcard arm. Prefer [`code`](Self::code)
+/// and the classification methods for anything beyond reporting.
 #[derive(Debug, Snafu)]
 #[snafu(visibility = "pub(crate)")]
+#[non_exhaustive]
 pub enum SpotifyError {
     #[snafu(display("Unable to read environment variable: {}", source))]
     EnvError { source: env::VarError },
@@ -24,8 +30,283 @@ pub enum SpotifyError {
     #[snafu(display("Callback URL parsing failure: {}", context))]
     CallbackFailure { context: &'static str },
 
-    #[snafu(display("Surf http failure: {}", source))]
-    SurfError {
+    #[snafu(display(
+        "Callback URL parsing failure: State does not match the authorization request."
+    ))]
+    StateMismatch,
+
+    #[snafu(display("Invalid state: {}", context))]
+    InvalidState { context: &'static str },
+
+    #[snafu(display("Authorization code replay: {}", context))]
+    CodeReplayed { context: &'static str },
+
+    #[snafu(display("Authorization denied: {}", reason))]
+    AuthorizationDenied { reason: String },
+
+    #[snafu(display("I/O failure: {}", source))]
+    IoError { source: io::Error },
+
+    #[cfg(feature = "sqlite")]
+    #[snafu(display("Database failure: {}", source))]
+    DatabaseError { source: rusqlite::Error },
+
+    #[snafu(display("Login failure: {}", context))]
+    LoginFailure { context: &'static str },
+
+    #[snafu(display("HTTP transport failure: {}", source))]
+    HttpError {
+        source: Box<dyn error::Error + Send + Sync>,
+    },
+
+    #[snafu(display("Unable to connect to the Spotify Accounts service: {}", source))]
+    ConnectError {
         source: Box<dyn error::Error + Send + Sync>,
     },
+
+    #[snafu(display("Token request failure: {} (HTTP {}){}", context, status,
+        if body.is_empty() { String::new() } else { format!(": {}", body) }))]
+    ResponseFailure {
+        context: &'static str,
+        status: u16,
+        /// The OAuth error code given in the response, such as ``invalid_grant``.
+        error: Option<String>,
+        body: String,
+    },
+
+    #[snafu(display(
+        "Unable to parse token response (HTTP {}): {}: {}",
+        status,
+        source,
+        body
+    ))]
+    ResponseParseFailure {
+        status: u16,
+        body: String,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Response body exceeds the limit of {} bytes", limit))]
+    ResponseTooLarge { limit: usize },
+
+    #[snafu(display("Invalid HTTP client configuration: {}", context))]
+    ConfigFailure { context: &'static str },
+
+    #[snafu(display("Token was issued to client {}, not to client {}", found, expected))]
+    ClientMismatch { expected: String, found: String },
+
+    #[snafu(display(
+        "Unsupported storage format version {}, written by a newer version of this crate",
+        version
+    ))]
+    UnsupportedFormat { version: u64 },
+
+    #[snafu(display("Capacity exceeded: {}", context))]
+    CapacityExceeded { context: &'static str },
+
+    #[snafu(display("{} (after {} attempts)", source, attempts))]
+    RetryFailure {
+        attempts: u32,
+        source: Box<SpotifyError>,
+    },
+}
+
+impl SpotifyError {
+    /// The number of attempts made before the request failed.
+    ///
+    /// This is only greater than one for token requests which were retried.
+    pub fn attempts(&self) -> u32 {
+        match self {
+            SpotifyError::RetryFailure { attempts, .. } => *attempts,
+            _ => 1,
+        }
+    }
+
+    /// A stable, machine-readable code for the kind of failure.
+    ///
+    /// | Code | Failure |
+    /// |------|---------|
+    /// | ``env_error`` | An environment variable is missing or invalid. |
+    /// | ``invalid_url`` | A configured URL, such as the redirect URI, could not be parsed. |
+    /// | ``invalid_json`` | JSON could not be parsed. |
+    /// | ``invalid_config`` | The HTTP client configuration is invalid. |
+    /// | ``invalid_callback`` | The callback URL is malformed. |
+    /// | ``state_mismatch`` | The callback state does not match the authorization request. |
+    /// | ``invalid_state`` | The state is unknown, has expired, was tampered with or has an unsafe return path. |
+    /// | ``code_replayed`` | The authorization code or state of the callback was already used. |
+    /// | ``access_denied`` | The user denied the authorization request. |
+    /// | ``missing_code`` | The callback contains no authorization code. |
+    /// | ``login_failure`` | The interactive login could not capture the callback. |
+    /// | ``io_error`` | A local I/O operation failed. |
+    /// | ``database_error`` | A query of the token database failed. |
+    /// | ``client_mismatch`` | A token issued to a different client was used or stored. |
+    /// | ``unsupported_format`` | A stored token or token database was written in a newer format. |
+    /// | ``connect_error`` | The Spotify Accounts service could not be reached. |
+    /// | ``http_error`` | The request failed in transit. |
+    /// | ``response_too_large`` | The response body exceeds the configured limit. |
+    /// | ``invalid_response`` | The token response could not be parsed. |
+    /// | ``invalid_grant`` | The authorization code or refresh token is invalid, expired or revoked. |
+    /// | ``invalid_client`` | The client credentials were rejected. |
+    /// | ``rate_limited`` | The Spotify Accounts service is rate limiting requests. |
+    /// | ``accounts_unavailable`` | The Spotify Accounts service failed with a server error. |
+    /// | ``token_request_failed`` | The token request was rejected for another reason. |
+    /// | ``capacity_exceeded`` | Too many authorization requests are waiting for their callback. |
+    ///
+    /// Retried requests report the code of the final failure.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use spotify_oauth::SpotifyCallback;
+    /// let callback = SpotifyCallback::new(Some("NApCCgBkWtQ".to_string()), None, String::from("test"));
+    /// assert_eq!(callback.validate_state("other").unwrap_err().code(), "state_mismatch");
+    /// ```
+    pub fn code(&self) -> &'static str {
+        match self {
+            SpotifyError::EnvError { .. } => "env_error",
+            SpotifyError::SerdeError { .. } => "invalid_json",
+            SpotifyError::UrlError { .. } => "invalid_url",
+            SpotifyError::TokenFailure { .. } => "missing_code",
+            SpotifyError::CallbackFailure { .. } => "invalid_callback",
+            SpotifyError::StateMismatch => "state_mismatch",
+            SpotifyError::InvalidState { .. } => "invalid_state",
+            SpotifyError::CodeReplayed { .. } => "code_replayed",
+            SpotifyError::AuthorizationDenied { .. } => "access_denied",
+            SpotifyError::IoError { .. } => "io_error",
+            #[cfg(feature = "sqlite")]
+            SpotifyError::DatabaseError { .. } => "database_error",
+            SpotifyError::LoginFailure { .. } => "login_failure",
+            SpotifyError::HttpError { .. } => "http_error",
+            SpotifyError::ConnectError { .. } => "connect_error",
+            SpotifyError::ResponseFailure { status, error, .. } => {
+                match (*status, error.as_deref()) {
+                    (_, Some("invalid_grant")) => "invalid_grant",
+                    (_, Some("invalid_client")) | (_, Some("unauthorized_client")) => {
+                        "invalid_client"
+                    }
+                    (429, _) => "rate_limited",
+                    (500..=599, _) => "accounts_unavailable",
+                    _ => "token_request_failed",
+                }
+            }
+            SpotifyError::ResponseParseFailure { .. } => "invalid_response",
+            SpotifyError::ResponseTooLarge { .. } => "response_too_large",
+            SpotifyError::ConfigFailure { .. } => "invalid_config",
+            SpotifyError::ClientMismatch { .. } => "client_mismatch",
+            SpotifyError::UnsupportedFormat { .. } => "unsupported_format",
+            SpotifyError::CapacityExceeded { .. } => "capacity_exceeded",
+            SpotifyError::RetryFailure { source, .. } => source.code(),
+        }
+    }
+
+    /// Whether the failure is transient, so the same request may succeed when tried again later.
+    ///
+    /// Transport failures, rate limiting, server errors and full stores are retryable. Retried requests which
+    /// ran out of attempts are still retryable.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.code(),
+            "connect_error"
+                | "http_error"
+                | "rate_limited"
+                | "accounts_unavailable"
+                | "capacity_exceeded"
+        )
+    }
+
+    /// Whether the user has to go through the authorization flow again.
+    ///
+    /// This is the case when the authorization code or refresh token was rejected with
+    /// ``invalid_grant``, for example because the user revoked access to the application.
+    pub fn requires_reauthorization(&self) -> bool {
+        self.code() == "invalid_grant"
+    }
+
+    /// Whether the failure is caused by the application configuration, such as missing
+    /// environment variables, invalid URLs, invalid HTTP client settings, rejected client credentials
+    /// or tokens of a different client.
+    pub fn is_configuration_error(&self) -> bool {
+        matches!(
+            self.code(),
+            "env_error" | "invalid_url" | "invalid_config" | "invalid_client" | "client_mismatch"
+        )
+    }
+
+    /// Whether the user denied the authorization request.
+    pub fn is_user_denied(&self) -> bool {
+        self.code() == "access_denied"
+    }
+
+    /// The OAuth error code returned by the Spotify Accounts service, such as ``invalid_grant``.
+    pub fn oauth_error(&self) -> Option<&str> {
+        match self {
+            SpotifyError::ResponseFailure { error, .. } => error.as_deref(),
+            SpotifyError::RetryFailure { source, .. } => source.oauth_error(),
+            _ => None,
+        }
+    }
+
+    /// The HTTP status of the response from the Spotify Accounts service, if one was received.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            SpotifyError::ResponseFailure { status, .. }
+            | SpotifyError::ResponseParseFailure { status, .. } => Some(*status),
+            SpotifyError::RetryFailure { source, .. } => source.status(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response_failure(status: u16, error: Option<&str>) -> SpotifyError {
+        SpotifyError::ResponseFailure {
+            context: "Failed to refresh token",
+            status,
+            error: error.map(String::from),
+            body: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_classification() {
+        let revoked = response_failure(400, Some("invalid_grant"));
+        assert_eq!(revoked.code(), "invalid_grant");
+        assert!(revoked.requires_reauthorization());
+        assert!(!revoked.is_retryable());
+
+        let rejected = response_failure(401, Some("invalid_client"));
+        assert!(rejected.is_configuration_error());
+        assert!(!rejected.requires_reauthorization());
+
+        let limited = response_failure(429, None);
+        assert_eq!(limited.code(), "rate_limited");
+        assert!(limited.is_retryable());
+
+        let denied = SpotifyError::AuthorizationDenied {
+            reason: "access_denied".into(),
+        };
+        assert!(denied.is_user_denied());
+        assert!(!denied.is_retryable());
+
+        let config = SpotifyError::EnvError {
+            source: env::VarError::NotPresent,
+        };
+        assert_eq!(config.code(), "env_error");
+        assert!(config.is_configuration_error());
+    }
+
+    #[test]
+    fn test_retry_failure_classification() {
+        let error = SpotifyError::RetryFailure {
+            attempts: 3,
+            source: Box::new(response_failure(503, None)),
+        };
+
+        assert_eq!(error.code(), "accounts_unavailable");
+        assert_eq!(error.status(), Some(503));
+        assert!(error.is_retryable());
+    }
 }
@@ -0,0 +1,526 @@
+//! Requests to the token endpoint of the Spotify Accounts service.
+//!
+//! Building the request and parsing the response is shared between the asynchronous and the
+//! blocking API, only the HTTP client used to send the request differs.
+
+use serde::Deserialize;
+use snafu::ResultExt;
+use url::Url;
+
+use std::time::Duration;
+
+use crate::clock::Clock;
+use crate::error::*;
+#[cfg(feature = "blocking")]
+use crate::http::BlockingHttpClient;
+use crate::http::{HttpClient, HttpRequest, HttpResponse};
+use crate::retry::RetryPolicy;
+use crate::rt;
+use crate::SpotifyToken;
+
+/// The maximum number of bytes of a response body kept in errors.
+const MAX_SNIPPET_SIZE: usize = 256;
+
+/// The grant used to request a token.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Grant<'a> {
+    /// Exchange an authorization code given by the callback.
+    AuthorizationCode {
+        code: &'a str,
+        redirect_uri: &'a Url,
+        code_verifier: Option<&'a str>,
+    },
+    /// Request a new access token with a refresh token.
+    RefreshToken { refresh_token: &'a str },
+    /// Request an access token for the application itself.
+    ClientCredentials,
+}
+
+impl<'a> Grant<'a> {
+    /// Build the request to the token endpoint authenticated with the client credentials.
+    pub(crate) fn request(
+        &self,
+        token_url: &str,
+        client_id: &str,
+        client_secret: &str,
+    ) -> HttpRequest {
+        let form: Vec<(&str, &str)> = match *self {
+            Grant::AuthorizationCode {
+                code,
+                redirect_uri,
+                code_verifier,
+            } => {
+                let mut form = vec![
+                    ("grant_type", "authorization_code"),
+                    ("code", code),
+                    ("redirect_uri", redirect_uri.as_str()),
+                ];
+                form.extend(code_verifier.map(|x| ("code_verifier", x)));
+                form
+            }
+            Grant::RefreshToken { refresh_token } => vec![
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh_token),
+            ],
+            Grant::ClientCredentials => vec![("grant_type", "client_credentials")],
+        };
+
+        // Form authorisation header.
+        let auth_value = base64::encode(&format!("{}:{}", client_id, client_secret));
+
+        HttpRequest::form(token_url, &form).header("Authorization", format!("Basic {}", auth_value))
+    }
+
+    /// Parse the response of the token endpoint into a token.
+    ///
+    /// The expiry timestamp of the token is computed from the current time of the clock.
+    pub(crate) fn parse(
+        &self,
+        response: HttpResponse,
+        clock: &dyn Clock,
+    ) -> SpotifyResult<SpotifyToken> {
+        if !response.is_success() {
+            return Err(SpotifyError::ResponseFailure {
+                context: match self {
+                    Grant::AuthorizationCode { .. } => "Failed to convert callback into token",
+                    Grant::RefreshToken { .. } => "Failed to refresh token",
+                    Grant::ClientCredentials => "Failed to request client credentials token",
+                },
+                status: response.status,
+                error: oauth_error(&response.body),
+                body: snippet(&response.body),
+            });
+        }
+
+        let mut token: SpotifyToken =
+            serde_json::from_slice(&response.body).context(ResponseParseFailure {
+                status: response.status,
+                body: snippet(&response.body),
+            })?;
+        let issued_at = clock.now().timestamp();
+        token.issued_at = Some(issued_at);
+        token.expires_at = Some(issued_at + i64::from(token.expires_in));
+
+        // Spotify only returns a refresh token when it has been rotated.
+        if let Grant::RefreshToken { refresh_token } = *self {
+            if token.refresh_token.is_empty() {
+                token.refresh_token = refresh_token.into();
+            }
+        }
+
+        Ok(token)
+    }
+
+    /// Whether the request can safely be sent again after it may have reached the server.
+    ///
+    /// An authorization code can only be exchanged once, so a second attempt could fail even
+    /// though the first one succeeded.
+    fn is_repeatable(&self) -> bool {
+        match self {
+            Grant::AuthorizationCode { .. } => false,
+            Grant::RefreshToken { .. } | Grant::ClientCredentials => true,
+        }
+    }
+
+    /// How long to wait before retrying after the given attempt, ``None`` if it should not be retried.
+    fn retry_delay(
+        &self,
+        retry: &RetryPolicy,
+        attempt: u32,
+        outcome: &SpotifyResult<HttpResponse>,
+        clock: &dyn Clock,
+    ) -> Option<Duration> {
+        let retry_after = match outcome {
+            // Rate limited requests are rejected before being processed.
+            Ok(response) if response.status == 429 => response.retry_after(clock),
+            Ok(response) if response.status >= 500 && self.is_repeatable() => {
+                response.retry_after(clock)
+            }
+            Ok(_) => return None,
+            // Nothing reached the server if the connection could not be made.
+            Err(SpotifyError::ConnectError { .. }) => None,
+            Err(SpotifyError::HttpError { .. }) if self.is_repeatable() => None,
+            Err(_) => return None,
+        };
+
+        retry.delay(attempt, retry_after)
+    }
+
+    /// Parse the outcome of the final attempt, recording how many attempts were made and the
+    /// client the token was issued to.
+    fn finish(
+        &self,
+        outcome: SpotifyResult<HttpResponse>,
+        attempts: u32,
+        client_id: &str,
+        clock: &dyn Clock,
+    ) -> SpotifyResult<SpotifyToken> {
+        let token = outcome.and_then(|x| self.parse(x, clock)).map(|mut x| {
+            x.client_id = Some(client_id.to_string());
+            x
+        });
+
+        match token {
+            Err(e) if attempts > 1 => Err(SpotifyError::RetryFailure {
+                attempts,
+                source: Box::new(e),
+            }),
+            x => x,
+        }
+    }
+
+    /// Request a token with an asynchronous HTTP client, retrying according to the policy.
+    pub(crate) async fn execute<C: HttpClient + ?Sized>(
+        self,
+        http: &C,
+        token_url: &str,
+        client_id: &str,
+        client_secret: &str,
+        retry: &RetryPolicy,
+        clock: &dyn Clock,
+    ) -> SpotifyResult<SpotifyToken> {
+        let mut attempt = 1;
+        loop {
+            let outcome = http
+                .execute(self.request(token_url, client_id, client_secret))
+                .await;
+
+            match self.retry_delay(retry, attempt, &outcome, clock) {
+                Some(delay) => rt::sleep(delay).await,
+                None => return self.finish(outcome, attempt, client_id, clock),
+            }
+
+            attempt += 1;
+        }
+    }
+
+    /// Request a token with a blocking HTTP client, retrying according to the policy.
+    #[cfg(feature = "blocking")]
+    pub(crate) fn execute_blocking<C: BlockingHttpClient + ?Sized>(
+        self,
+        http: &C,
+        token_url: &str,
+        client_id: &str,
+        client_secret: &str,
+        retry: &RetryPolicy,
+        clock: &dyn Clock,
+    ) -> SpotifyResult<SpotifyToken> {
+        let mut attempt = 1;
+        loop {
+            let outcome = http.execute(self.request(token_url, client_id, client_secret));
+
+            match self.retry_delay(retry, attempt, &outcome, clock) {
+                Some(delay) => std::thread::sleep(delay),
+                None => return self.finish(outcome, attempt, client_id, clock),
+            }
+
+            attempt += 1;
+        }
+    }
+}
+
+/// The OAuth error code of an error response body, such as ``invalid_grant``.
+fn oauth_error(body: &[u8]) -> Option<String> {
+    #[derive(Deserialize)]
+    struct ErrorResponse {
+        error: String,
+    }
+
+    serde_json::from_slice::<ErrorResponse>(body)
+        .ok()
+        .map(|x| x.error)
+}
+
+/// The start of a response body for error messages, cut to ``MAX_SNIPPET_SIZE`` bytes.
+fn snippet(body: &[u8]) -> String {
+    let mut snippet =
+        String::from_utf8_lossy(&body[..body.len().min(MAX_SNIPPET_SIZE)]).into_owned();
+    if body.len() > MAX_SNIPPET_SIZE {
+        snippet.push_str("...");
+    }
+    snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{ManualClock, SystemClock};
+    use crate::{ScriptedHttpClient, SpotifyScope, SPOTIFY_TOKEN_URL};
+    use chrono::{TimeZone, Utc};
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_grant_request() {
+        let redirect_uri = Url::parse("http://localhost:8888/callback").unwrap();
+        let request = Grant::AuthorizationCode {
+            code: "a b",
+            redirect_uri: &redirect_uri,
+            code_verifier: None,
+        }
+        .request(SPOTIFY_TOKEN_URL, "id", "secret");
+
+        assert_eq!(
+            request.body,
+            b"grant_type=authorization_code&code=a+b&redirect_uri=http%3A%2F%2Flocalhost%3A8888%2Fcallback".to_vec()
+        );
+        assert!(request
+            .headers
+            .contains(&("Authorization", "Basic aWQ6c2VjcmV0".to_string())));
+
+        let request = Grant::AuthorizationCode {
+            code: "a",
+            redirect_uri: &redirect_uri,
+            code_verifier: Some("v"),
+        }
+        .request(SPOTIFY_TOKEN_URL, "id", "secret");
+        assert!(request.body.ends_with(b"&code_verifier=v"));
+
+        let request = Grant::RefreshToken {
+            refresh_token: "NgAagAHfVxDkSvCUm_SHo",
+        }
+        .request(SPOTIFY_TOKEN_URL, "id", "secret");
+        assert_eq!(
+            request.body,
+            b"grant_type=refresh_token&refresh_token=NgAagAHfVxDkSvCUm_SHo".to_vec()
+        );
+
+        let request = Grant::ClientCredentials.request(SPOTIFY_TOKEN_URL, "id", "secret");
+        assert_eq!(request.body, b"grant_type=client_credentials".to_vec());
+    }
+
+    #[test]
+    fn test_parse_token_response() {
+        let response = HttpResponse::new(
+            200,
+            r#"{"access_token":"NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw","token_type":"Bearer","scope":"streaming","expires_in":3600,"refresh_token":"NgAagAHfVxDkSvCUm_SHo"}"#,
+        );
+
+        let clock = ManualClock::new(Utc.timestamp_opt(1_577_836_800, 0).unwrap());
+        let token = Grant::ClientCredentials.parse(response, &clock).unwrap();
+        assert_eq!(token.scope, vec![SpotifyScope::Streaming]);
+        assert_eq!(token.issued_at, Some(1_577_836_800));
+        assert_eq!(token.expires_at, Some(1_577_840_400));
+
+        assert_eq!(
+            Grant::ClientCredentials
+                .parse(
+                    HttpResponse::new(400, r#"{"error":"invalid_client"}"#),
+                    &SystemClock
+                )
+                .unwrap_err()
+                .to_string(),
+            r#"Token request failure: Failed to request client credentials token (HTTP 400): {"error":"invalid_client"}"#
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_response() {
+        let error = Grant::ClientCredentials
+            .parse(HttpResponse::new(502, vec![b'x'; 4096]), &SystemClock)
+            .unwrap_err();
+        assert_eq!(error.status(), Some(502));
+        match error {
+            SpotifyError::ResponseFailure { body, .. } => {
+                assert_eq!(body.len(), MAX_SNIPPET_SIZE + 3)
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Truncated and non UTF-8 bodies fail without panicking.
+        for body in [
+            &br#"{"access_token":"NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw","token_ty"#[..],
+            &[0xff, 0xfe, 0x7b][..],
+        ] {
+            let error = Grant::ClientCredentials
+                .parse(HttpResponse::new(200, body), &SystemClock)
+                .unwrap_err();
+            assert!(matches!(
+                error,
+                SpotifyError::ResponseParseFailure { status: 200, .. }
+            ));
+        }
+
+        // Scopes this crate does not know are skipped.
+        let token = Grant::ClientCredentials
+            .parse(
+                HttpResponse::new(
+                    200,
+                    r#"{"access_token":"Ng","token_type":"Bearer","scope":"ugc-image-upload streaming user-read-playback-position","expires_in":3600}"#,
+                ),
+                &SystemClock,
+            )
+            .unwrap();
+        assert_eq!(token.scope, vec![SpotifyScope::Streaming]);
+    }
+
+    #[test]
+    fn test_refresh_keeps_refresh_token() {
+        let grant = Grant::RefreshToken {
+            refresh_token: "NgAagAHfVxDkSvCUm_SHo",
+        };
+
+        // Spotify leaves out the refresh token and scope when it hasn't been rotated.
+        let token = grant
+            .parse(
+                HttpResponse::new(
+                    200,
+                    r#"{"access_token":"NgA6ZcYIixn8bU","token_type":"Bearer","expires_in":3600}"#,
+                ),
+                &SystemClock,
+            )
+            .unwrap();
+        assert_eq!(token.refresh_token.expose_secret(), "NgAagAHfVxDkSvCUm_SHo");
+
+        let token = grant
+            .parse(HttpResponse::new(
+                200,
+                r#"{"access_token":"NgA6ZcYIixn8bU","token_type":"Bearer","scope":"","expires_in":3600,"refresh_token":"AQBrotated"}"#,
+            ), &SystemClock)
+            .unwrap();
+        assert_eq!(token.refresh_token.expose_secret(), "AQBrotated");
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            base_delay: Duration::from_millis(1),
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn test_refresh_retries_server_errors() {
+        let http = ScriptedHttpClient::new();
+        http.push_response(HttpResponse::new(503, ""));
+        http.push_error(SpotifyError::HttpError {
+            source: "connection reset".into(),
+        });
+        http.push_response(HttpResponse::new(
+            200,
+            r#"{"access_token":"NgA6ZcYIixn8bU","token_type":"Bearer","expires_in":3600}"#,
+        ));
+
+        let grant = Grant::RefreshToken {
+            refresh_token: "NgAagAHfVxDkSvCUm_SHo",
+        };
+        let token = block_on(grant.execute(
+            &http,
+            SPOTIFY_TOKEN_URL,
+            "id",
+            "secret",
+            &policy(),
+            &SystemClock,
+        ))
+        .unwrap();
+
+        assert_eq!(token.access_token.expose_secret(), "NgA6ZcYIixn8bU");
+        assert_eq!(http.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_retries_exhausted() {
+        let http = ScriptedHttpClient::new();
+        for _ in 0..3 {
+            http.push_response(HttpResponse::new(500, ""));
+        }
+
+        let error = block_on(Grant::ClientCredentials.execute(
+            &http,
+            SPOTIFY_TOKEN_URL,
+            "id",
+            "secret",
+            &policy(),
+            &SystemClock,
+        ))
+        .unwrap_err();
+
+        assert_eq!(error.attempts(), 3);
+        assert_eq!(
+            error.to_string(),
+            "Token request failure: Failed to request client credentials token (HTTP 500) (after 3 attempts)"
+        );
+    }
+
+    #[test]
+    fn test_never_retries_invalid_grant() {
+        let http = ScriptedHttpClient::new();
+        http.push_response(HttpResponse::new(400, r#"{"error":"invalid_grant"}"#));
+
+        let grant = Grant::RefreshToken {
+            refresh_token: "NgAagAHfVxDkSvCUm_SHo",
+        };
+        let error = block_on(grant.execute(
+            &http,
+            SPOTIFY_TOKEN_URL,
+            "id",
+            "secret",
+            &policy(),
+            &SystemClock,
+        ))
+        .unwrap_err();
+
+        assert_eq!(error.attempts(), 1);
+        assert_eq!(http.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_code_exchange_only_retried_before_processing() {
+        let redirect_uri = Url::parse("http://localhost:8888/callback").unwrap();
+        let grant = Grant::AuthorizationCode {
+            code: "AQD0yXvFEOvw",
+            redirect_uri: &redirect_uri,
+            code_verifier: None,
+        };
+
+        // The code may have been consumed by a request that failed on the server.
+        let http = ScriptedHttpClient::new();
+        http.push_response(HttpResponse::new(502, ""));
+        assert!(block_on(grant.execute(
+            &http,
+            SPOTIFY_TOKEN_URL,
+            "id",
+            "secret",
+            &policy(),
+            &SystemClock
+        ))
+        .is_err());
+        assert_eq!(http.requests().len(), 1);
+
+        let http = ScriptedHttpClient::new();
+        http.push_error(SpotifyError::HttpError {
+            source: "timed out".into(),
+        });
+        assert!(block_on(grant.execute(
+            &http,
+            SPOTIFY_TOKEN_URL,
+            "id",
+            "secret",
+            &policy(),
+            &SystemClock
+        ))
+        .is_err());
+        assert_eq!(http.requests().len(), 1);
+
+        // Rate limited and unconnected requests never reached the token endpoint.
+        let http = ScriptedHttpClient::new();
+        let mut limited = HttpResponse::new(429, "");
+        limited.headers.push(("retry-after".into(), "0".into()));
+        http.push_response(limited);
+        http.push_error(SpotifyError::ConnectError {
+            source: "connection refused".into(),
+        });
+        http.push_response(HttpResponse::new(
+            200,
+            r#"{"access_token":"NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw","token_type":"Bearer","scope":"","expires_in":3600,"refresh_token":"NgAagAHfVxDkSvCUm_SHo"}"#,
+        ));
+        assert!(block_on(grant.execute(
+            &http,
+            SPOTIFY_TOKEN_URL,
+            "id",
+            "secret",
+            &policy(),
+            &SystemClock
+        ))
+        .is_ok());
+        assert_eq!(http.requests().len(), 3);
+    }
+}
@@ -0,0 +1,875 @@
+//! HTTP transport used for calls to the Spotify Accounts service.
+//!
+//! Every request made by the library goes through either the [`HttpClient`] or the
+//! [`BlockingHttpClient`] trait, so the underlying HTTP library can be swapped out.
+//! Adapters are available behind the following cargo features:
+//!
+//! - ``surf-client`` (default): [`SurfClient`] using surf.
+//! - ``reqwest-client``: [`ReqwestClient`] using reqwest.
+//! - ``ureq-client``: [`UreqClient`] using the blocking ureq client.
+//!
+//! Each adapter can be built from a [`HttpConfig`] using its ``from_config`` function, which sets
+//! the timeouts, proxy, extra root certificates and User-Agent. The reqwest and ureq adapters
+//! default to [`HttpConfig::from_env`], the surf adapter to [`HttpConfig::default`] as it cannot
+//! use a proxy.
+//!
+//! The [`ScriptedHttpClient`] replays canned responses and is useful for testing.
+
+use async_trait::async_trait;
+use snafu::ResultExt;
+use url::form_urlencoded;
+
+use chrono::DateTime;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[cfg(feature = "surf-client")]
+use surf_futures::io::AsyncReadExt;
+
+use crate::clock::Clock;
+#[cfg(any(
+    feature = "surf-client",
+    feature = "reqwest-client",
+    feature = "ureq-client"
+))]
+use crate::config::HttpConfig;
+use crate::error::*;
+
+/// A HTTP POST request to the Spotify Accounts service.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpRequest {
+    /// The URL the request is sent to.
+    pub url: String,
+    /// The request headers.
+    pub headers: Vec<(&'static str, String)>,
+    /// The request body.
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Create a new request with a URL encoded form body.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use spotify_oauth::HttpRequest;
+    /// let request = HttpRequest::form("https://accounts.spotify.com/api/token", &[("grant_type", "client_credentials")]);
+    /// # assert_eq!(request.body, b"grant_type=client_credentials");
+    /// ```
+    pub fn form(url: &str, form: &[(&str, &str)]) -> Self {
+        let body = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(form)
+            .finish();
+
+        Self {
+            url: url.to_owned(),
+            headers: vec![(
+                "Content-Type",
+                "application/x-www-form-urlencoded".to_owned(),
+            )],
+            body: body.into_bytes(),
+        }
+    }
+
+    /// Add a header to the request.
+    pub fn header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+}
+
+/// A HTTP response from the Spotify Accounts service.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpResponse {
+    /// The response status code.
+    pub status: u16,
+    /// The response headers.
+    pub headers: Vec<(String, String)>,
+    /// The response body.
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// Create a new response with the given status and body and no headers.
+    pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: body.into(),
+        }
+    }
+
+    /// Whether the status code is in the 2xx range.
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// The value of the first header with the given name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|x| x.0.eq_ignore_ascii_case(name))
+            .map(|x| x.1.as_str())
+    }
+
+    /// How long the server asked to wait before retrying, from the ``Retry-After`` header.
+    ///
+    /// Both the delay in seconds and the HTTP date forms are supported, dates are compared with
+    /// the current time of the clock.
+    pub fn retry_after(&self, clock: &dyn Clock) -> Option<Duration> {
+        let value = self.header("Retry-After")?.trim();
+
+        if let Ok(x) = value.parse::<u64>() {
+            return Some(Duration::from_secs(x));
+        }
+
+        let date = DateTime::parse_from_rfc2822(value).ok()?;
+        let remaining = date.timestamp() - clock.now().timestamp();
+        Some(Duration::from_secs(remaining.max(0) as u64))
+    }
+}
+
+/// An asynchronous HTTP client used to make requests to the Spotify Accounts service.
+#[async_trait]
+pub trait HttpClient: Send + Sync {
+    /// Send the request and return the response, whatever its status code.
+    async fn execute(&self, request: HttpRequest) -> SpotifyResult<HttpResponse>;
+}
+
+#[async_trait]
+impl<T: HttpClient + ?Sized> HttpClient for &T {
+    async fn execute(&self, request: HttpRequest) -> SpotifyResult<HttpResponse> {
+        (**self).execute(request).await
+    }
+}
+
+#[async_trait]
+impl<T: HttpClient + ?Sized> HttpClient for Arc<T> {
+    async fn execute(&self, request: HttpRequest) -> SpotifyResult<HttpResponse> {
+        (**self).execute(request).await
+    }
+}
+
+/// A blocking HTTP client used to make requests to the Spotify Accounts service.
+pub trait BlockingHttpClient: Send + Sync {
+    /// Send the request and return the response, whatever its status code.
+    fn execute(&self, request: HttpRequest) -> SpotifyResult<HttpResponse>;
+}
+
+/// The asynchronous client used when none is given explicitly.
+#[cfg(feature = "surf-client")]
+pub type DefaultHttpClient = SurfClient;
+
+/// The asynchronous client used when none is given explicitly.
+#[cfg(all(not(feature = "surf-client"), feature = "reqwest-client"))]
+pub type DefaultHttpClient = ReqwestClient;
+
+/// A HTTP client which replays scripted responses and records the requests made.
+///
+/// # Example
+///
+/// ```
+/// # use spotify_oauth::{BlockingHttpClient, HttpRequest, HttpResponse, ScriptedHttpClient};
+/// let client = ScriptedHttpClient::new();
+/// client.push_response(HttpResponse::new(400, r#"{"error":"invalid_grant"}"#));
+///
+/// let response = BlockingHttpClient::execute(&client, HttpRequest::form("https://example.com", &[])).unwrap();
+/// assert_eq!(response.status, 400);
+/// assert_eq!(client.requests().len(), 1);
+/// ```
+#[derive(Debug, Default)]
+pub struct ScriptedHttpClient {
+    responses: Mutex<VecDeque<SpotifyResult<HttpResponse>>>,
+    requests: Mutex<Vec<HttpRequest>>,
+}
+
+impl ScriptedHttpClient {
+    /// Create a new client with no scripted responses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a response to be returned by a future request.
+    pub fn push_response(&self, response: HttpResponse) -> &Self {
+        self.responses.lock().unwrap().push_back(Ok(response));
+        self
+    }
+
+    /// Queue a transport error to be returned by a future request.
+    pub fn push_error(&self, error: SpotifyError) -> &Self {
+        self.responses.lock().unwrap().push_back(Err(error));
+        self
+    }
+
+    /// The requests made so far, in order.
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn next_response(&self, request: HttpRequest) -> SpotifyResult<HttpResponse> {
+        self.requests.lock().unwrap().push(request);
+        self.responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| Err("No scripted response left".into()).context(HttpError))
+    }
+}
+
+#[async_trait]
+impl HttpClient for ScriptedHttpClient {
+    async fn execute(&self, request: HttpRequest) -> SpotifyResult<HttpResponse> {
+        self.next_response(request)
+    }
+}
+
+impl BlockingHttpClient for ScriptedHttpClient {
+    fn execute(&self, request: HttpRequest) -> SpotifyResult<HttpResponse> {
+        self.next_response(request)
+    }
+}
+
+/// A HTTP client using surf.
+///
+/// Surf has no proxy or certificate settings, so a configuration with either is rejected. The
+/// ``connect_timeout`` is not supported and is ignored, connecting is only limited by the
+/// ``timeout`` of the whole request.
+#[cfg(feature = "surf-client")]
+#[derive(Debug, Clone)]
+pub struct SurfClient {
+    timeout: Option<Duration>,
+    user_agent: String,
+    max_response_size: usize,
+}
+
+#[cfg(feature = "surf-client")]
+impl SurfClient {
+    /// Create a new client using the given configuration.
+    pub fn from_config(config: &HttpConfig) -> SpotifyResult<Self> {
+        if config.proxy.is_some() {
+            return ConfigFailure {
+                context: "The surf client does not support proxies.",
+            }
+            .fail();
+        }
+
+        if !config.root_certificates.is_empty() {
+            return ConfigFailure {
+                context: "The surf client does not support custom root certificates.",
+            }
+            .fail();
+        }
+
+        Ok(Self {
+            timeout: config.timeout,
+            user_agent: config.user_agent.clone(),
+            max_response_size: config.max_response_size,
+        })
+    }
+
+    /// Send the request without a timeout.
+    async fn send(&self, request: HttpRequest) -> SpotifyResult<HttpResponse> {
+        // Without a length the body is sent chunked after waiting for ``100 Continue``.
+        let mut req = surf::post(&request.url)
+            .set_header("Content-Length", request.body.len().to_string())
+            .set_header("User-Agent", &self.user_agent)
+            .body_bytes(&request.body);
+        for (name, value) in &request.headers {
+            req = req.set_header(name, value);
+        }
+
+        let mut response = match req.await {
+            Ok(x) => x,
+            Err(e) if is_surf_connect_error(&*e) => return Err(e).context(ConnectError),
+            Err(e) => return Err(e).context(HttpError),
+        };
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .collect();
+        let status = response.status().as_u16();
+
+        // Reject declared oversized bodies before reading, and stop reading past the limit.
+        let length = response
+            .header("Content-Length")
+            .and_then(|x| x.parse().ok());
+        if length.is_some_and(|x: usize| x > self.max_response_size) {
+            return ResponseTooLarge {
+                limit: self.max_response_size,
+            }
+            .fail();
+        }
+
+        let mut body = Vec::new();
+        response
+            .take(self.max_response_size as u64 + 1)
+            .read_to_end(&mut body)
+            .await
+            .map_err(|e| e.into())
+            .context(HttpError)?;
+        check_body_size(&body, self.max_response_size)?;
+
+        Ok(HttpResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+#[cfg(feature = "surf-client")]
+impl Default for SurfClient {
+    fn default() -> Self {
+        let config = HttpConfig::default();
+        Self {
+            timeout: config.timeout,
+            user_agent: config.user_agent,
+            max_response_size: config.max_response_size,
+        }
+    }
+}
+
+#[cfg(feature = "surf-client")]
+#[async_trait]
+impl HttpClient for SurfClient {
+    async fn execute(&self, request: HttpRequest) -> SpotifyResult<HttpResponse> {
+        let response = self.send(request);
+        let timeout = match self.timeout {
+            Some(x) => x,
+            None => return response.await,
+        };
+
+        futures::pin_mut!(response);
+        let sleep = crate::rt::sleep(timeout);
+        futures::pin_mut!(sleep);
+
+        match futures::future::select(response, sleep).await {
+            futures::future::Either::Left((x, _)) => x,
+            futures::future::Either::Right(_) => {
+                Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "request timed out").into())
+                    .context(HttpError)
+            }
+        }
+    }
+}
+
+/// Whether the surf request failed before a connection to the server was established.
+#[cfg(feature = "surf-client")]
+fn is_surf_connect_error(error: &(dyn std::error::Error + 'static)) -> bool {
+    matches!(
+        error.downcast_ref::<isahc::Error>(),
+        Some(isahc::Error::ConnectFailed)
+            | Some(isahc::Error::CouldntResolveHost)
+            | Some(isahc::Error::CouldntResolveProxy)
+    )
+}
+
+/// A HTTP client using reqwest.
+#[cfg(feature = "reqwest-client")]
+#[derive(Debug, Clone)]
+pub struct ReqwestClient {
+    client: reqwest::Client,
+    max_response_size: usize,
+}
+
+#[cfg(feature = "reqwest-client")]
+impl ReqwestClient {
+    /// Create a new client wrapping an existing reqwest client.
+    ///
+    /// Response bodies are limited to the size given by [`HttpConfig::default`].
+    pub fn new(client: reqwest::Client) -> Self {
+        Self {
+            client,
+            max_response_size: HttpConfig::default().max_response_size,
+        }
+    }
+
+    /// Create a new client using the given configuration.
+    pub fn from_config(config: &HttpConfig) -> SpotifyResult<Self> {
+        use snafu::OptionExt;
+
+        let mut builder = reqwest::Client::builder().user_agent(config.user_agent.as_str());
+
+        if let Some(x) = config.connect_timeout {
+            builder = builder.connect_timeout(x);
+        }
+
+        if let Some(x) = config.timeout {
+            builder = builder.timeout(x);
+        }
+
+        // Only the configured proxy is used, not the one reqwest finds in the environment.
+        builder = match config.proxy {
+            Some(ref x) => builder.proxy(reqwest::Proxy::all(x.as_str()).ok().context(
+                ConfigFailure {
+                    context: "Invalid proxy URL.",
+                },
+            )?),
+            None => builder.no_proxy(),
+        };
+
+        for pem in &config.root_certificates {
+            let certificates = reqwest::Certificate::from_pem_bundle(pem)
+                .ok()
+                .filter(|x| !x.is_empty())
+                .context(ConfigFailure {
+                    context: "Invalid root certificate.",
+                })?;
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        let client = builder.build().ok().context(ConfigFailure {
+            context: "Failed to build the reqwest client.",
+        })?;
+
+        Ok(Self {
+            client,
+            max_response_size: config.max_response_size,
+        })
+    }
+}
+
+/// Implementation of Default for ReqwestClient.
+///
+/// Uses [`HttpConfig::from_env`], falling back to [`HttpConfig::default`] without a proxy if the
+/// proxy given by the environment is invalid.
+#[cfg(feature = "reqwest-client")]
+impl Default for ReqwestClient {
+    fn default() -> Self {
+        Self::from_config_or_default(&HttpConfig::from_env())
+    }
+}
+
+#[cfg(feature = "reqwest-client")]
+impl ReqwestClient {
+    /// Build a client from the configuration, or from the default configuration if it is invalid.
+    fn from_config_or_default(config: &HttpConfig) -> Self {
+        Self::from_config(config)
+            .or_else(|_| Self::from_config(&HttpConfig::default()))
+            .expect("Failed to build the reqwest client.")
+    }
+}
+
+#[cfg(feature = "reqwest-client")]
+#[async_trait]
+impl HttpClient for ReqwestClient {
+    async fn execute(&self, request: HttpRequest) -> SpotifyResult<HttpResponse> {
+        let mut req = self.client.post(&request.url).body(request.body);
+        for (name, value) in request.headers {
+            req = req.header(name, value);
+        }
+
+        let mut response = match req.send().await {
+            Ok(x) => x,
+            Err(e) if e.is_connect() => return Err(Box::new(e).into()).context(ConnectError),
+            Err(e) => return Err(Box::new(e).into()).context(HttpError),
+        };
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(k, v)| {
+                (
+                    k.to_string(),
+                    String::from_utf8_lossy(v.as_bytes()).into_owned(),
+                )
+            })
+            .collect();
+        let mut body = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| e.into())
+            .context(HttpError)?
+        {
+            body.extend_from_slice(&chunk);
+            check_body_size(&body, self.max_response_size)?;
+        }
+
+        Ok(HttpResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+/// A blocking HTTP client using ureq.
+#[cfg(feature = "ureq-client")]
+#[derive(Debug, Clone)]
+pub struct UreqClient {
+    agent: ureq::Agent,
+    max_response_size: usize,
+}
+
+#[cfg(feature = "ureq-client")]
+impl UreqClient {
+    /// Create a new client wrapping an existing ureq agent.
+    ///
+    /// Response bodies are limited to the size given by [`HttpConfig::default`].
+    pub fn new(agent: ureq::Agent) -> Self {
+        Self {
+            agent,
+            max_response_size: HttpConfig::default().max_response_size,
+        }
+    }
+
+    /// Create a new client using the given configuration.
+    pub fn from_config(config: &HttpConfig) -> SpotifyResult<Self> {
+        use snafu::OptionExt;
+
+        let mut builder = ureq::AgentBuilder::new().user_agent(&config.user_agent);
+
+        if let Some(x) = config.connect_timeout {
+            builder = builder.timeout_connect(x);
+        }
+
+        if let Some(x) = config.timeout {
+            builder = builder.timeout(x);
+        }
+
+        if let Some(ref x) = config.proxy {
+            let proxy = ureq::Proxy::new(x.as_str()).ok().context(ConfigFailure {
+                context: "Invalid proxy URL.",
+            })?;
+            builder = builder.proxy(proxy);
+        }
+
+        if !config.root_certificates.is_empty() {
+            builder = builder.tls_config(std::sync::Arc::new(ureq_tls_config(
+                &config.root_certificates,
+            )?));
+        }
+
+        Ok(Self {
+            agent: builder.build(),
+            max_response_size: config.max_response_size,
+        })
+    }
+}
+
+/// A TLS configuration trusting the built-in root certificates and the given PEM certificates.
+#[cfg(feature = "ureq-client")]
+fn ureq_tls_config(root_certificates: &[Vec<u8>]) -> SpotifyResult<rustls::ClientConfig> {
+    use rustls_pki_types::pem::PemObject;
+    use rustls_pki_types::CertificateDer;
+    use snafu::OptionExt;
+
+    let mut roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+
+    for pem in root_certificates {
+        let mut found = false;
+        for certificate in CertificateDer::pem_slice_iter(pem) {
+            let certificate = certificate.ok().context(ConfigFailure {
+                context: "Invalid root certificate.",
+            })?;
+            roots.add(certificate).ok().context(ConfigFailure {
+                context: "Invalid root certificate.",
+            })?;
+            found = true;
+        }
+
+        if !found {
+            return ConfigFailure {
+                context: "Invalid root certificate.",
+            }
+            .fail();
+        }
+    }
+
+    let provider = std::sync::Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .ok()
+        .context(ConfigFailure {
+            context: "Failed to build the TLS configuration.",
+        })?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(config)
+}
+
+/// Implementation of Default for UreqClient.
+///
+/// Uses [`HttpConfig::from_env`], falling back to [`HttpConfig::default`] without a proxy if the
+/// proxy given by the environment is invalid.
+#[cfg(feature = "ureq-client")]
+impl Default for UreqClient {
+    fn default() -> Self {
+        Self::from_config_or_default(&HttpConfig::from_env())
+    }
+}
+
+#[cfg(feature = "ureq-client")]
+impl UreqClient {
+    /// Build an agent from the configuration, or from the default configuration if it is invalid.
+    fn from_config_or_default(config: &HttpConfig) -> Self {
+        Self::from_config(config)
+            .or_else(|_| Self::from_config(&HttpConfig::default()))
+            .expect("Failed to build the ureq agent.")
+    }
+}
+
+#[cfg(feature = "ureq-client")]
+impl BlockingHttpClient for UreqClient {
+    fn execute(&self, request: HttpRequest) -> SpotifyResult<HttpResponse> {
+        let mut req = self.agent.post(&request.url);
+        for (name, value) in &request.headers {
+            req = req.set(name, value);
+        }
+
+        // Error statuses are still responses the caller should see.
+        let response = match req.send_bytes(&request.body) {
+            Ok(x) | Err(ureq::Error::Status(_, x)) => x,
+            Err(ureq::Error::Transport(e)) => {
+                let connect = matches!(
+                    e.kind(),
+                    ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed
+                );
+
+                // Nothing was sent when the connection could not be made.
+                return if connect {
+                    Err(Box::new(e).into()).context(ConnectError)
+                } else {
+                    Err(Box::new(e).into()).context(HttpError)
+                };
+            }
+        };
+
+        let status = response.status();
+        let headers = response
+            .headers_names()
+            .into_iter()
+            .filter_map(|k| {
+                let v = response.header(&k)?.to_owned();
+                Some((k, v))
+            })
+            .collect();
+
+        let mut body = Vec::new();
+        let limit = self.max_response_size as u64 + 1;
+        std::io::Read::read_to_end(
+            &mut std::io::Read::take(response.into_reader(), limit),
+            &mut body,
+        )
+        .map_err(|e| e.into())
+        .context(HttpError)?;
+        check_body_size(&body, self.max_response_size)?;
+
+        Ok(HttpResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+/// Fail if the body read so far is larger than the limit.
+#[cfg(any(
+    feature = "surf-client",
+    feature = "reqwest-client",
+    feature = "ureq-client"
+))]
+fn check_body_size(body: &[u8], limit: usize) -> SpotifyResult<()> {
+    if body.len() > limit {
+        return ResponseTooLarge { limit }.fail();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scripted_client_runs_out() {
+        let client = ScriptedHttpClient::new();
+
+        assert!(
+            BlockingHttpClient::execute(&client, HttpRequest::form("http://localhost", &[]))
+                .is_err()
+        );
+        assert_eq!(client.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let clock = crate::ManualClock::new(
+            DateTime::parse_from_rfc2822("Wed, 01 Jan 2020 00:00:00 GMT")
+                .unwrap()
+                .into(),
+        );
+        let mut response = HttpResponse::new(429, "");
+        assert_eq!(response.retry_after(&clock), None);
+
+        response.headers = vec![("retry-after".into(), "30".into())];
+        assert_eq!(response.retry_after(&clock), Some(Duration::from_secs(30)));
+
+        // Dates are relative to the clock, and dates in the past mean no delay.
+        response.headers = vec![("Retry-After".into(), "Wed, 01 Jan 2020 00:02:00 GMT".into())];
+        assert_eq!(response.retry_after(&clock), Some(Duration::from_secs(120)));
+        clock.advance(Duration::from_secs(180));
+        assert_eq!(response.retry_after(&clock), Some(Duration::from_secs(0)));
+    }
+
+    #[cfg(feature = "surf-client")]
+    #[test]
+    fn test_surf_config() {
+        let config = HttpConfig {
+            proxy: Some(url::Url::parse("http://proxy.internal:3128").unwrap()),
+            ..HttpConfig::default()
+        };
+        assert_eq!(
+            SurfClient::from_config(&config).unwrap_err().to_string(),
+            "Invalid HTTP client configuration: The surf client does not support proxies."
+        );
+
+        // Accept the connection but never respond.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/token", listener.local_addr().unwrap());
+
+        let config = HttpConfig {
+            timeout: Some(Duration::from_millis(200)),
+            ..HttpConfig::default()
+        };
+        let client = SurfClient::from_config(&config).unwrap();
+        let result =
+            futures::executor::block_on(HttpClient::execute(&client, HttpRequest::form(&url, &[])));
+        assert!(result.unwrap_err().to_string().contains("timed out"));
+
+        // Nothing listens on the port once the listener is dropped.
+        drop(listener);
+        let result =
+            futures::executor::block_on(HttpClient::execute(&client, HttpRequest::form(&url, &[])));
+        assert_eq!(result.unwrap_err().code(), "connect_error");
+    }
+
+    #[cfg(any(feature = "reqwest-client", feature = "ureq-client"))]
+    #[test]
+    fn test_invalid_proxy_falls_back() {
+        let config = HttpConfig {
+            proxy: Some(url::Url::parse("ftp://proxy.internal:21").unwrap()),
+            ..HttpConfig::default()
+        };
+
+        #[cfg(feature = "reqwest-client")]
+        {
+            assert!(ReqwestClient::from_config(&config).is_err());
+            let _ = ReqwestClient::from_config_or_default(&config);
+        }
+
+        #[cfg(feature = "ureq-client")]
+        {
+            assert!(UreqClient::from_config(&config).is_err());
+            let _ = UreqClient::from_config_or_default(&config);
+        }
+    }
+
+    #[cfg(any(feature = "reqwest-client", feature = "ureq-client"))]
+    #[test]
+    fn test_invalid_root_certificate() {
+        let config = HttpConfig {
+            root_certificates: vec![b"not a certificate".to_vec()],
+            ..HttpConfig::default()
+        };
+
+        #[cfg(feature = "reqwest-client")]
+        assert!(ReqwestClient::from_config(&config).is_err());
+
+        #[cfg(feature = "ureq-client")]
+        assert!(UreqClient::from_config(&config).is_err());
+    }
+
+    /// Serve a single response with a body of the given size, declaring its length if asked to.
+    #[cfg(any(feature = "surf-client", feature = "ureq-client"))]
+    fn serve_body(size: usize, declare_length: bool) -> String {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/token", listener.local_addr().unwrap());
+
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0u8; 4096]);
+            let length = if declare_length {
+                format!("Content-Length: {}\r\n", size)
+            } else {
+                String::new()
+            };
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\n{}Connection: close\r\n\r\n{}",
+                length,
+                "x".repeat(size)
+            );
+        });
+
+        url
+    }
+
+    #[cfg(any(feature = "surf-client", feature = "ureq-client"))]
+    #[test]
+    fn test_response_size_limit() {
+        let config = HttpConfig {
+            max_response_size: 1024,
+            ..HttpConfig::default()
+        };
+
+        #[cfg(feature = "surf-client")]
+        {
+            let client = SurfClient::from_config(&config).unwrap();
+            let result = futures::executor::block_on(HttpClient::execute(
+                &client,
+                HttpRequest::form(&serve_body(4096, true), &[]),
+            ));
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "Response body exceeds the limit of 1024 bytes"
+            );
+
+            // Bodies without a length stop being read at the limit.
+            let result = futures::executor::block_on(HttpClient::execute(
+                &client,
+                HttpRequest::form(&serve_body(1 << 20, false), &[]),
+            ));
+            assert!(matches!(
+                result,
+                Err(SpotifyError::ResponseTooLarge { limit: 1024 })
+            ));
+
+            let response = futures::executor::block_on(HttpClient::execute(
+                &client,
+                HttpRequest::form(&serve_body(1024, false), &[]),
+            ));
+            assert_eq!(response.unwrap().body.len(), 1024);
+        }
+
+        #[cfg(feature = "ureq-client")]
+        {
+            let client = UreqClient::from_config(&config).unwrap();
+            let result = BlockingHttpClient::execute(
+                &client,
+                HttpRequest::form(&serve_body(4096, true), &[]),
+            );
+            assert!(matches!(
+                result,
+                Err(SpotifyError::ResponseTooLarge { limit: 1024 })
+            ));
+
+            let response = BlockingHttpClient::execute(
+                &client,
+                HttpRequest::form(&serve_body(1024, true), &[]),
+            );
+            assert_eq!(response.unwrap().body.len(), 1024);
+        }
+    }
+}
@@ -32,28 +32,115 @@
 //! }
 //! ```
 
-use chrono::{DateTime, Utc};
 use dotenv::dotenv;
 use rand::{self, Rng};
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
 use snafu::ResultExt;
 use strum_macros::{Display, EnumString};
 use url::Url;
 
-use std::collections::HashMap;
 use std::env;
 use std::str::FromStr;
 use std::string::ToString;
 
+mod client;
+pub use crate::client::SpotifyOAuthClient;
+
+mod clock;
+pub use crate::clock::{Clock, ManualClock, SystemClock};
+
+mod config;
+pub use crate::config::{HttpConfig, DEFAULT_USER_AGENT};
+
+mod envelope;
+pub use crate::envelope::{TokenEnvelope, TOKEN_FORMAT_VERSION};
+
 mod error;
-use crate::error::{SerdeError, *};
+use crate::error::*;
+pub use crate::error::{SpotifyError, SpotifyResult};
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+
+mod grant;
+use crate::grant::Grant;
+
+mod http;
+#[cfg(any(feature = "surf-client", feature = "reqwest-client"))]
+pub use crate::http::DefaultHttpClient;
+#[cfg(feature = "reqwest-client")]
+pub use crate::http::ReqwestClient;
+#[cfg(feature = "surf-client")]
+pub use crate::http::SurfClient;
+#[cfg(feature = "ureq-client")]
+pub use crate::http::UreqClient;
+pub use crate::http::{
+    BlockingHttpClient, HttpClient, HttpRequest, HttpResponse, ScriptedHttpClient,
+};
+
+mod lifetime;
+pub use crate::lifetime::{TokenLifetime, DEFAULT_CLOCK_SKEW};
+
+mod login;
+#[cfg(any(feature = "surf-client", feature = "reqwest-client"))]
+pub use crate::login::login;
+pub use crate::login::{login_with, LoginStrategy, LOGIN_TIMEOUT};
+
+mod manager;
+#[cfg(any(feature = "runtime-tokio", feature = "runtime-async-std"))]
+pub use crate::manager::RefresherHandle;
+pub use crate::manager::{TokenManager, TokenUpdates};
+
+mod pending;
+pub use crate::pending::{MemoryPendingStore, PendingAuthorization, PendingAuthorizationStore};
+
+mod problem;
+pub use crate::problem::{Problem, PROBLEM_CONTENT_TYPE};
+
+mod replay;
+pub use crate::replay::ReplayGuard;
+
+mod retry;
+pub use crate::retry::RetryPolicy;
+
+mod rt;
+
+mod scheduler;
+pub use crate::scheduler::{RefreshReport, RefreshScheduler};
+
+mod secret;
+pub use crate::secret::SecretString;
+
+mod signed_state;
+pub use crate::signed_state::{SignedState, StateSigner};
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use crate::sqlite::SqliteTokenStore;
+
+mod state;
+pub use crate::state::{generate_state, StateGenerator};
+
+mod store;
+pub use crate::store::{
+    FileTokenStore, MemoryUserTokenStore, TokenLock, TokenStore, UserTokenStore,
+};
+
+#[cfg(feature = "watch")]
+mod watch;
+#[cfg(feature = "watch")]
+pub use crate::watch::TokenWatcher;
 
 const SPOTIFY_AUTH_URL: &str = "https://accounts.spotify.com/authorize";
 const SPOTIFY_TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
 
 /// Convert date and time to a unix timestamp.
 ///
+/// Uses the [`SystemClock`], token requests made with a [`SpotifyOAuthClient`] or a
+/// [`TokenManager`] use the clock they are given instead.
+///
 /// # Example
 ///
 /// ```no_run
@@ -62,12 +149,14 @@ const SPOTIFY_TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
 /// let timestamp = datetime_to_timestamp(3600);
 /// ```
 pub fn datetime_to_timestamp(elapsed: u32) -> i64 {
-    let utc: DateTime<Utc> = Utc::now();
-    utc.timestamp() + i64::from(elapsed)
+    crate::clock::timestamp_after(&SystemClock, elapsed)
 }
 
 /// Generate a random alphanumeric string with a given length.
 ///
+/// This uses a non-cryptographic generator, use [`generate_state`] for the ``state`` parameter
+/// of authorization requests.
+///
 /// # Example
 ///
 /// ```no_run
@@ -161,7 +250,7 @@ pub struct SpotifyAuth {
     /// The Spotify Application Client ID
     pub client_id: String,
     /// The Spotify Application Client Secret
-    pub client_secret: String,
+    pub client_secret: SecretString,
     /// Required by the Spotify API.
     pub response_type: String,
     /// The URI to redirect to after the user grants or denies permission.
@@ -179,7 +268,7 @@ pub struct SpotifyAuth {
 /// If ``CLIENT_ID`` is not found in the ``.env`` in the project directory it will default to ``INVALID_ID``.
 /// If ``REDIRECT_ID`` is not found in the ``.env`` in the project directory it will default to ``http://localhost:8000/callback``.
 ///
-/// This implementation automatically generates a state value of length 20 using a random string generator.
+/// This implementation automatically generates a state value using [`generate_state`].
 ///
 impl Default for SpotifyAuth {
     fn default() -> Self {
@@ -188,12 +277,15 @@ impl Default for SpotifyAuth {
 
         Self {
             client_id: env::var("SPOTIFY_CLIENT_ID").context(EnvError).unwrap(),
-            client_secret: env::var("SPOTIFY_CLIENT_SECRET").context(EnvError).unwrap(),
+            client_secret: env::var("SPOTIFY_CLIENT_SECRET")
+                .context(EnvError)
+                .unwrap()
+                .into(),
             response_type: "code".to_owned(),
             redirect_uri: Url::parse(&env::var("REDIRECT_URI").context(EnvError).unwrap())
                 .context(UrlError)
                 .unwrap(),
-            state: generate_random_string(20),
+            state: generate_state(),
             scope: vec![],
             show_dialog: false,
         }
@@ -207,7 +299,7 @@ impl SpotifyAuth {
     /// This function loads ``SPOTIFY_CLIENT_ID`` and ``SPOTIFY_REDIRECT_ID`` from values given in
     /// function parameters.
     ///
-    /// This function also automatically generates a state value of length 20 using a random string generator.
+    /// This function also automatically generates a state value using [`generate_state`].
     ///
     /// # Example
     ///
@@ -227,10 +319,10 @@ impl SpotifyAuth {
     ) -> Self {
         Self {
             client_id,
-            client_secret,
+            client_secret: client_secret.into(),
             response_type,
             redirect_uri: Url::parse(&redirect_uri).context(UrlError).unwrap(),
-            state: generate_random_string(20),
+            state: generate_state(),
             scope,
             show_dialog,
         }
@@ -240,7 +332,7 @@ impl SpotifyAuth {
     ///
     /// This function loads ``SPOTIFY_CLIENT_ID`` and ``SPOTIFY_REDIRECT_ID`` from the environment.
     ///
-    /// This function also automatically generates a state value of length 20 using a random string generator.
+    /// This function also automatically generates a state value using [`generate_state`].
     ///
     /// # Example
     ///
@@ -260,12 +352,15 @@ impl SpotifyAuth {
 
         Self {
             client_id: env::var("SPOTIFY_CLIENT_ID").context(EnvError).unwrap(),
-            client_secret: env::var("SPOTIFY_CLIENT_SECRET").context(EnvError).unwrap(),
+            client_secret: env::var("SPOTIFY_CLIENT_SECRET")
+                .context(EnvError)
+                .unwrap()
+                .into(),
             response_type,
             redirect_uri: Url::parse(&env::var("SPOTIFY_REDIRECT_URI").context(EnvError).unwrap())
                 .context(UrlError)
                 .unwrap(),
-            state: generate_random_string(20),
+            state: generate_state(),
             scope,
             show_dialog,
         }
@@ -359,7 +454,15 @@ impl FromStr for SpotifyCallback {
     type Err = error::SpotifyError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let url = Url::parse(s).context(UrlError)?;
+        let url = match Url::parse(s) {
+            Ok(x) => x,
+            Err(_) => {
+                return CallbackFailure {
+                    context: "Not a valid URL.",
+                }
+                .fail()
+            }
+        };
         let parsed: Vec<(String, String)> = url
             .query_pairs()
             .map(|x| (x.0.into_owned(), x.1.into_owned()))
@@ -428,8 +531,34 @@ impl SpotifyCallback {
         Self { code, error, state }
     }
 
+    /// The state returned in the callback.
+    pub fn state(&self) -> &str {
+        &self.state
+    }
+
+    /// Check that the state returned in the callback matches the state sent with the authorization request.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use spotify_oauth::SpotifyCallback;
+    /// let callback = SpotifyCallback::new(Some("NApCCgBkWtQ".to_string()), None, String::from("test"));
+    /// assert!(callback.validate_state("test").is_ok());
+    /// assert!(callback.validate_state("other").is_err());
+    /// ```
+    pub fn validate_state(&self, state: &str) -> SpotifyResult<()> {
+        if self.state != state {
+            return Err(SpotifyError::StateMismatch);
+        }
+
+        Ok(())
+    }
+
     /// Converts the Spotify Callback object into a Spotify Token object.
     ///
+    /// The request is made with the [`DefaultHttpClient`], use [`convert_into_token_with`](Self::convert_into_token_with)
+    /// to provide a different HTTP client.
+    ///
     /// # Example
     ///
     /// ```no_run
@@ -445,51 +574,78 @@ impl SpotifyCallback {
     ///     .convert_into_token(auth.client_id, auth.client_secret, auth.redirect_uri).await.unwrap();
     /// # Ok(()) }
     /// ```
+    #[cfg(any(feature = "surf-client", feature = "reqwest-client"))]
     pub async fn convert_into_token(
         self,
         client_id: String,
-        client_secret: String,
+        client_secret: SecretString,
         redirect_uri: Url,
     ) -> SpotifyResult<SpotifyToken> {
-        let mut payload: HashMap<String, String> = HashMap::new();
-        payload.insert("grant_type".to_owned(), "authorization_code".to_owned());
-        payload.insert(
-            "code".to_owned(),
-            match self.code {
-                None => {
-                    return Err(SpotifyError::TokenFailure {
-                        context: "Spotify callback code failed to parse.",
-                    })
-                }
-                Some(x) => x,
-            },
-        );
-        payload.insert("redirect_uri".to_owned(), redirect_uri.to_string());
-
-        // Form authorisation header.
-        let auth_value = base64::encode(&format!("{}:{}", client_id, client_secret));
-
-        // POST the request.
-        let mut response = surf::post(SPOTIFY_TOKEN_URL)
-            .set_header("Authorization", format!("Basic {}", auth_value))
-            .body_form(&payload)
-            .unwrap()
-            .await
-            .context(SurfError)?;
-
-        // Read the response body.
-        let buf = response.body_string().await.unwrap();
+        self.convert_into_token_with(
+            &DefaultHttpClient::default(),
+            client_id,
+            client_secret,
+            redirect_uri,
+        )
+        .await
+    }
 
-        if response.status().is_success() {
-            let mut token: SpotifyToken = serde_json::from_str(&buf).context(SerdeError)?;
-            token.expires_at = Some(datetime_to_timestamp(token.expires_in));
+    /// Converts the Spotify Callback object into a Spotify Token object using the given HTTP client.
+    ///
+    /// Requests which were rate limited or could not connect are retried with the default [`RetryPolicy`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use spotify_oauth::{HttpResponse, ScriptedHttpClient, SpotifyCallback};
+    /// # use std::str::FromStr;
+    /// # futures::executor::block_on(async {
+    /// // Script the response of the token endpoint.
+    /// let client = ScriptedHttpClient::new();
+    /// client.push_response(HttpResponse::new(200, r#"{"access_token":"NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw","token_type":"Bearer","scope":"streaming","expires_in":3600,"refresh_token":"NgAagAHfVxDkSvCUm_SHo"}"#));
+    ///
+    /// let token = SpotifyCallback::from_str("https://example.com/callback?code=NApCCgBkWtQ&state=test").unwrap()
+    ///     .convert_into_token_with(&client, "00000000000".into(), "secret".into(), "http://localhost:8000/callback".parse().unwrap()).await.unwrap();
+    /// # assert_eq!(token.access_token.expose_secret(), "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw");
+    /// # });
+    /// ```
+    pub async fn convert_into_token_with<C: HttpClient + ?Sized>(
+        self,
+        http: &C,
+        client_id: String,
+        client_secret: SecretString,
+        redirect_uri: Url,
+    ) -> SpotifyResult<SpotifyToken> {
+        Grant::AuthorizationCode {
+            code: self.authorization_code()?,
+            redirect_uri: &redirect_uri,
+            code_verifier: None,
+        }
+        .execute(
+            http,
+            SPOTIFY_TOKEN_URL,
+            &client_id,
+            client_secret.expose_secret(),
+            &RetryPolicy::default(),
+            &SystemClock,
+        )
+        .await
+    }
 
-            return Ok(token);
+    /// The authorization code of the callback, failing if authorization was not granted.
+    pub(crate) fn authorization_code(&self) -> SpotifyResult<&str> {
+        if let Some(ref reason) = self.error {
+            return Err(SpotifyError::AuthorizationDenied {
+                reason: reason.clone(),
+            });
         }
 
-        Err(SpotifyError::TokenFailure {
-            context: "Failed to convert callback into token",
-        })
+        match self.code {
+            None => Err(SpotifyError::TokenFailure {
+                context: "Spotify callback code failed to parse.",
+            }),
+            Some(ref x) => Ok(x),
+        }
     }
 }
 
@@ -514,41 +670,283 @@ impl SpotifyCallback {
 ///     .convert_into_token(auth.client_id, auth.client_secret, auth.redirect_uri).await.unwrap();
 /// # Ok(()) }
 /// ```
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct SpotifyToken {
     /// An access token that can be provided in subsequent calls, for example to Spotify Web API services.
-    pub access_token: String,
+    pub access_token: SecretString,
     /// How the access token may be used.
     pub token_type: String,
     /// A Vec of scopes which have been granted for this ``access_token``.
-    #[serde(deserialize_with = "deserialize_scope_field")]
+    #[serde(
+        default,
+        serialize_with = "serialize_scope_field",
+        deserialize_with = "deserialize_scope_field"
+    )]
     pub scope: Vec<SpotifyScope>,
     /// The time period (in seconds) for which the access token is valid.
     pub expires_in: u32,
     /// The timestamp for which the token will expire at.
     pub expires_at: Option<i64>,
+    /// The timestamp at which the token was issued, ``None`` if it is unknown.
+    #[serde(default)]
+    pub issued_at: Option<i64>,
     /// A token that can be sent to the Spotify Accounts service in place of an authorization code to request a new ``access_token``.
-    pub refresh_token: String,
+    ///
+    /// This is empty for tokens obtained with the client credentials flow.
+    #[serde(default)]
+    pub refresh_token: SecretString,
+    /// The client ID of the application the token was issued to, ``None`` if it is unknown.
+    ///
+    /// Refresh tokens only work with the application which they were issued to, so refreshing
+    /// or storing the token with a different client ID is rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+}
+
+/// Refresh and client credentials requests for SpotifyToken.
+impl SpotifyToken {
+    /// Request a new access token using the refresh token of this token.
+    ///
+    /// If Spotify does not rotate the refresh token, the current one is kept in the new token.
+    /// The request is made with the [`DefaultHttpClient`], use [`refresh_with`](Self::refresh_with)
+    /// to provide a different HTTP client.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use spotify_oauth::{FileTokenStore, TokenStore};
+    /// # #[async_std::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    /// let token = FileTokenStore::new("token.json").load()?.unwrap();
+    /// let token = token.refresh("00000000000", "secret").await?;
+    /// # Ok(()) }
+    /// ```
+    #[cfg(any(feature = "surf-client", feature = "reqwest-client"))]
+    pub async fn refresh(&self, client_id: &str, client_secret: &str) -> SpotifyResult<Self> {
+        self.refresh_with(&DefaultHttpClient::default(), client_id, client_secret)
+            .await
+    }
+
+    /// Request a new access token using the refresh token of this token and the given HTTP client.
+    ///
+    /// Transient failures are retried with the default [`RetryPolicy`].
+    pub async fn refresh_with<C: HttpClient + ?Sized>(
+        &self,
+        http: &C,
+        client_id: &str,
+        client_secret: &str,
+    ) -> SpotifyResult<Self> {
+        self.verify_client(client_id)?;
+
+        Grant::RefreshToken {
+            refresh_token: self.refresh_token.expose_secret(),
+        }
+        .execute(
+            http,
+            SPOTIFY_TOKEN_URL,
+            client_id,
+            client_secret,
+            &RetryPolicy::default(),
+            &SystemClock,
+        )
+        .await
+    }
+
+    /// Check that the token was issued to the given client.
+    ///
+    /// Tokens without a client ID, such as tokens stored by older versions of the crate, are
+    /// accepted.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use spotify_oauth::SpotifyToken;
+    /// let mut token: SpotifyToken = serde_json::from_str(r#"{"access_token":"NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw","token_type":"Bearer","expires_in":3600}"#).unwrap();
+    /// token.client_id = Some("staging".into());
+    ///
+    /// assert_eq!(token.verify_client("production").unwrap_err().code(), "client_mismatch");
+    /// ```
+    pub fn verify_client(&self, client_id: &str) -> SpotifyResult<()> {
+        match self.client_id {
+            Some(ref x) if x != client_id => ClientMismatch {
+                expected: client_id,
+                found: x.as_str(),
+            }
+            .fail(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Request a token using only a stored refresh token.
+    ///
+    /// Unattended jobs which only keep a long-lived refresh token can get a complete token with
+    /// scopes and expiry this way, without going through the authorization callback. The request is
+    /// made with the [`DefaultHttpClient`], use [`from_refresh_token_with`](Self::from_refresh_token_with)
+    /// to provide a different HTTP client.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use spotify_oauth::SpotifyToken;
+    /// # #[async_std::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    /// let token = SpotifyToken::from_refresh_token("00000000000", "secret", "NgAagAHfVxDkSvCUm_SHo").await?;
+    /// # Ok(()) }
+    /// ```
+    #[cfg(any(feature = "surf-client", feature = "reqwest-client"))]
+    pub async fn from_refresh_token(
+        client_id: &str,
+        client_secret: &str,
+        refresh_token: &str,
+    ) -> SpotifyResult<Self> {
+        Self::from_refresh_token_with(
+            &DefaultHttpClient::default(),
+            client_id,
+            client_secret,
+            refresh_token,
+        )
+        .await
+    }
+
+    /// Request a token using only a stored refresh token and the given HTTP client.
+    ///
+    /// Transient failures are retried with the default [`RetryPolicy`].
+    pub async fn from_refresh_token_with<C: HttpClient + ?Sized>(
+        http: &C,
+        client_id: &str,
+        client_secret: &str,
+        refresh_token: &str,
+    ) -> SpotifyResult<Self> {
+        Grant::RefreshToken { refresh_token }
+            .execute(
+                http,
+                SPOTIFY_TOKEN_URL,
+                client_id,
+                client_secret,
+                &RetryPolicy::default(),
+                &SystemClock,
+            )
+            .await
+    }
+
+    /// Request a token using the client credentials and refresh token in the environment.
+    ///
+    /// This function loads ``SPOTIFY_CLIENT_ID``, ``SPOTIFY_CLIENT_SECRET`` and ``SPOTIFY_REFRESH_TOKEN``
+    /// from the environment and the ``.env`` file, see [`from_refresh_token`](Self::from_refresh_token).
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use spotify_oauth::SpotifyToken;
+    /// # #[async_std::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    /// let token = SpotifyToken::from_env().await?;
+    /// # Ok(()) }
+    /// ```
+    #[cfg(any(feature = "surf-client", feature = "reqwest-client"))]
+    pub async fn from_env() -> SpotifyResult<Self> {
+        Self::from_env_with(&DefaultHttpClient::default()).await
+    }
+
+    /// Request a token using the credentials in the environment and the given HTTP client.
+    pub async fn from_env_with<C: HttpClient + ?Sized>(http: &C) -> SpotifyResult<Self> {
+        let (client_id, client_secret, refresh_token) = refresh_credentials_from_env()?;
+
+        Self::from_refresh_token_with(
+            http,
+            &client_id,
+            client_secret.expose_secret(),
+            refresh_token.expose_secret(),
+        )
+        .await
+    }
+
+    /// Request an access token for the application itself using the client credentials flow.
+    ///
+    /// Tokens obtained this way can't access user data and have no refresh token.
+    /// The request is made with the [`DefaultHttpClient`], use [`client_credentials_with`](Self::client_credentials_with)
+    /// to provide a different HTTP client.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use spotify_oauth::SpotifyToken;
+    /// # #[async_std::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    /// let token = SpotifyToken::client_credentials("00000000000", "secret").await?;
+    /// # Ok(()) }
+    /// ```
+    #[cfg(any(feature = "surf-client", feature = "reqwest-client"))]
+    pub async fn client_credentials(client_id: &str, client_secret: &str) -> SpotifyResult<Self> {
+        Self::client_credentials_with(&DefaultHttpClient::default(), client_id, client_secret).await
+    }
+
+    /// Request an access token for the application itself using the given HTTP client.
+    ///
+    /// Transient failures are retried with the default [`RetryPolicy`].
+    pub async fn client_credentials_with<C: HttpClient + ?Sized>(
+        http: &C,
+        client_id: &str,
+        client_secret: &str,
+    ) -> SpotifyResult<Self> {
+        Grant::ClientCredentials
+            .execute(
+                http,
+                SPOTIFY_TOKEN_URL,
+                client_id,
+                client_secret,
+                &RetryPolicy::default(),
+                &SystemClock,
+            )
+            .await
+    }
+}
+
+/// Load the client ID, client secret and refresh token from the environment and the ``.env`` file.
+fn refresh_credentials_from_env() -> SpotifyResult<(String, SecretString, SecretString)> {
+    // Load local .env file.
+    dotenv().ok();
+
+    Ok((
+        env::var("SPOTIFY_CLIENT_ID").context(EnvError)?,
+        env::var("SPOTIFY_CLIENT_SECRET").context(EnvError)?.into(),
+        env::var("SPOTIFY_REFRESH_TOKEN").context(EnvError)?.into(),
+    ))
+}
+
+/// Custom serializing function for converting SpotifyScope Enums back into the space separated
+/// string given by the Spotify API, so stored tokens can be parsed again.
+fn serialize_scope_field<S>(scope: &[SpotifyScope], se: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    se.serialize_str(
+        &scope
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<String>>()
+            .join(" "),
+    )
 }
 
 /// Custom parsing function for converting a vector of string scopes into SpotifyScope Enums using Serde.
-/// If scope is empty it will return an empty vector.
+/// If scope is empty it will return an empty vector. Scopes unknown to [`SpotifyScope`], such as
+/// ones Spotify added later, are skipped rather than failing the whole token.
 fn deserialize_scope_field<'de, D>(de: D) -> Result<Vec<SpotifyScope>, D::Error>
 where
     D: Deserializer<'de>,
 {
     let result: Value = Deserialize::deserialize(de)?;
     match result {
-        Value::String(ref s) => {
-            let split: Vec<&str> = s.split_whitespace().collect();
-            let mut parsed: Vec<SpotifyScope> = Vec::new();
-
-            for x in split {
-                parsed.push(SpotifyScope::from_str(x).unwrap());
-            }
-
-            Ok(parsed)
-        }
+        Value::String(ref s) => Ok(s
+            .split_whitespace()
+            .filter_map(|x| SpotifyScope::from_str(x).ok())
+            .collect()),
+        // Tokens saved by older versions hold an array of variant names, such as ``Streaming``.
+        Value::Array(values) => Ok(values
+            .into_iter()
+            .filter_map(|x| serde_json::from_value(x).ok())
+            .collect()),
         _ => Ok(vec![]),
     }
 }
@@ -556,6 +954,7 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono::{TimeZone, Utc};
 
     // Callback Testing
 
@@ -577,6 +976,12 @@ mod tests {
             SpotifyCallback::from_str(&url).unwrap(),
             SpotifyCallback::new(None, Some("access_denied".to_string()), "sN".to_string())
         );
+
+        let error = SpotifyCallback::from_str(&url)
+            .unwrap()
+            .authorization_code()
+            .unwrap_err();
+        assert!(error.is_user_denied());
     }
 
     #[test]
@@ -611,20 +1016,76 @@ mod tests {
            "refresh_token": "NgAagAHfVxDkSvCUm_SHo"
         }"#;
 
+        let clock = ManualClock::new(Utc.timestamp_opt(1_577_836_800, 0).unwrap());
         let mut token: SpotifyToken = serde_json::from_str(token_json).unwrap();
-        let timestamp = datetime_to_timestamp(token.expires_in);
+        let timestamp = crate::clock::timestamp_after(&clock, token.expires_in);
         token.expires_at = Some(timestamp);
+        assert_eq!(timestamp, 1_577_840_400);
 
         assert_eq!(
             SpotifyToken {
-                access_token: "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw".to_string(),
+                access_token: "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw".into(),
                 token_type: "Bearer".to_string(),
                 scope: vec![SpotifyScope::UserReadPrivate, SpotifyScope::UserReadEmail],
                 expires_in: 3600,
                 expires_at: Some(timestamp),
-                refresh_token: "NgAagAHfVxDkSvCUm_SHo".to_string()
+                issued_at: None,
+                refresh_token: "NgAagAHfVxDkSvCUm_SHo".into(),
+                client_id: None,
             },
             token
         );
     }
+
+    #[test]
+    fn test_client_credentials() {
+        let http = ScriptedHttpClient::new();
+        http.push_response(HttpResponse::new(
+            200,
+            r#"{"access_token":"NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw","token_type":"Bearer","expires_in":3600}"#,
+        ));
+
+        let token = futures::executor::block_on(SpotifyToken::client_credentials_with(
+            &http, "id", "secret",
+        ))
+        .unwrap();
+
+        assert_eq!(
+            token.access_token.expose_secret(),
+            "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw"
+        );
+        assert!(token.scope.is_empty());
+        assert!(token.refresh_token.is_empty());
+        assert!(!format!("{:?}", token).contains("NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw"));
+        assert_eq!(
+            http.requests()[0].body,
+            b"grant_type=client_credentials".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_from_refresh_token() {
+        let http = ScriptedHttpClient::new();
+        http.push_response(HttpResponse::new(
+            200,
+            r#"{"access_token":"NgA6ZcYIixn8bU","token_type":"Bearer","scope":"streaming","expires_in":3600}"#,
+        ));
+
+        let token = futures::executor::block_on(SpotifyToken::from_refresh_token_with(
+            &http,
+            "id",
+            "secret",
+            "NgAagAHfVxDkSvCUm_SHo",
+        ))
+        .unwrap();
+
+        assert_eq!(token.access_token.expose_secret(), "NgA6ZcYIixn8bU");
+        assert_eq!(token.refresh_token.expose_secret(), "NgAagAHfVxDkSvCUm_SHo");
+        assert_eq!(token.scope, vec![SpotifyScope::Streaming]);
+        assert!(!token.is_expired());
+        assert_eq!(
+            http.requests()[0].body,
+            b"grant_type=refresh_token&refresh_token=NgAagAHfVxDkSvCUm_SHo".to_vec()
+        );
+    }
 }
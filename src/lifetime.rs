@@ -0,0 +1,172 @@
+//! Expiry checks for Spotify Tokens.
+
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::SpotifyToken;
+
+/// How much earlier than its expiry timestamp a token is considered expired by default, to allow
+/// for differences between the local clock and the clock of Spotify.
+pub const DEFAULT_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+/// The lifetime of a token at the current time of a clock.
+///
+/// Tokens expire at their ``expires_at`` timestamp, or ``expires_in`` seconds after ``issued_at``
+/// if only that is known. Tokens without either, such as tokens deserialized from a raw token
+/// response, have an unknown expiry and are always considered expired, so they are refreshed
+/// before use.
+///
+/// # Example
+///
+/// ```
+/// # use spotify_oauth::{Clock, ManualClock, SpotifyToken};
+/// # use std::time::Duration;
+/// # let mut token: SpotifyToken = serde_json::from_str(r#"{"access_token":"NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw","token_type":"Bearer","expires_in":3600}"#).unwrap();
+/// let clock = ManualClock::new(chrono::Utc::now());
+/// token.expires_at = Some(clock.now().timestamp() + 3600);
+///
+/// let lifetime = token.lifetime(&clock).with_clock_skew(Duration::from_secs(5));
+/// assert!(!lifetime.is_expired());
+/// assert!(lifetime.expires_within(Duration::from_secs(3600)));
+/// assert_eq!(lifetime.remaining(), Duration::from_secs(3595));
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct TokenLifetime<'a> {
+    expires_at: Option<i64>,
+    clock: &'a dyn Clock,
+    clock_skew: Duration,
+}
+
+impl<'a> TokenLifetime<'a> {
+    /// Consider the token expired the given duration before its expiry timestamp, defaults to
+    /// [`DEFAULT_CLOCK_SKEW`].
+    pub fn with_clock_skew(mut self, clock_skew: Duration) -> Self {
+        self.clock_skew = clock_skew;
+        self
+    }
+
+    /// The unix timestamp at which the token expires, ``None`` if it is unknown.
+    pub fn expires_at(&self) -> Option<i64> {
+        self.expires_at
+    }
+
+    /// How long the token remains valid, zero if it has expired or its expiry is unknown.
+    pub fn remaining(&self) -> Duration {
+        let expires_at = match self.expires_at {
+            Some(x) => x,
+            None => return Duration::from_secs(0),
+        };
+
+        let remaining =
+            expires_at - self.clock.now().timestamp() - self.clock_skew.as_secs() as i64;
+        Duration::from_secs(remaining.max(0) as u64)
+    }
+
+    /// Whether the token has expired or its expiry is unknown.
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == Duration::from_secs(0)
+    }
+
+    /// Whether the token expires within the given duration, or its expiry is unknown.
+    pub fn expires_within(&self, margin: Duration) -> bool {
+        self.remaining() <= margin
+    }
+}
+
+/// Expiry checks for SpotifyToken.
+impl SpotifyToken {
+    /// The lifetime of the token at the current time of the given clock.
+    pub fn lifetime<'a>(&self, clock: &'a dyn Clock) -> TokenLifetime<'a> {
+        TokenLifetime {
+            expires_at: self.estimated_expires_at(),
+            clock,
+            clock_skew: DEFAULT_CLOCK_SKEW,
+        }
+    }
+
+    /// The unix timestamp at which the token expires, estimated from ``issued_at`` and
+    /// ``expires_in`` if the token has no ``expires_at``.
+    pub(crate) fn estimated_expires_at(&self) -> Option<i64> {
+        self.expires_at.or_else(|| {
+            self.issued_at
+                .map(|issued_at| issued_at + i64::from(self.expires_in))
+        })
+    }
+
+    /// Whether the token has expired, see [`TokenLifetime::is_expired`].
+    pub fn is_expired(&self) -> bool {
+        self.lifetime(&SystemClock).is_expired()
+    }
+
+    /// Whether the token expires within the given duration, see [`TokenLifetime::expires_within`].
+    pub fn expires_within(&self, margin: Duration) -> bool {
+        self.lifetime(&SystemClock).expires_within(margin)
+    }
+
+    /// How long the token remains valid, see [`TokenLifetime::remaining`].
+    pub fn remaining(&self) -> Duration {
+        self.lifetime(&SystemClock).remaining()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ManualClock;
+    use chrono::{TimeZone, Utc};
+
+    const START: i64 = 1_577_836_800;
+
+    fn token(issued_at: Option<i64>, expires_at: Option<i64>) -> SpotifyToken {
+        SpotifyToken {
+            access_token: "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw".into(),
+            token_type: "Bearer".to_string(),
+            scope: vec![],
+            expires_in: 3600,
+            expires_at,
+            issued_at,
+            refresh_token: "NgAagAHfVxDkSvCUm_SHo".into(),
+            client_id: None,
+        }
+    }
+
+    #[test]
+    fn test_lifetime() {
+        let clock = ManualClock::new(Utc.timestamp_opt(START, 0).unwrap());
+        let token = token(Some(START), Some(START + 3600));
+
+        assert_eq!(
+            token.lifetime(&clock).remaining(),
+            Duration::from_secs(3570)
+        );
+        assert!(!token
+            .lifetime(&clock)
+            .expires_within(Duration::from_secs(60)));
+
+        clock.advance(Duration::from_secs(3580));
+        assert!(token
+            .lifetime(&clock)
+            .expires_within(Duration::from_secs(60)));
+        assert!(!token
+            .lifetime(&clock)
+            .with_clock_skew(Duration::from_secs(0))
+            .is_expired());
+        assert!(token.lifetime(&clock).is_expired());
+    }
+
+    #[test]
+    fn test_missing_expiry() {
+        let clock = ManualClock::new(Utc.timestamp_opt(START, 0).unwrap());
+
+        // The expiry is estimated from the issue time.
+        let estimated = token(Some(START), None);
+        assert_eq!(estimated.lifetime(&clock).expires_at(), Some(START + 3600));
+        assert!(!estimated.lifetime(&clock).is_expired());
+
+        // Without either the token is considered expired.
+        let unknown = token(None, None);
+        assert_eq!(unknown.lifetime(&clock).expires_at(), None);
+        assert!(unknown.lifetime(&clock).is_expired());
+        assert_eq!(unknown.remaining(), Duration::from_secs(0));
+    }
+}
@@ -0,0 +1,581 @@
+//! Interactive login helper for command line tools.
+//!
+//! The [`login`] function runs the whole Authorization Code Flow in a single call: it builds the
+//! authorization URL, presents it to the user, captures the callback, validates the state,
+//! exchanges the code for a token and optionally persists the token in a [`TokenStore`].
+//!
+//! Browsers are only launched when the ``browser`` feature is enabled, otherwise the
+//! authorization URL is printed for the user to open.
+
+use futures::channel::oneshot;
+use snafu::ResultExt;
+use url::{Host, Url};
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::*;
+use crate::http::HttpClient;
+use crate::store::TokenStore;
+use crate::{SpotifyAuth, SpotifyCallback, SpotifyToken};
+
+/// How often the loopback listener checks whether the login has been abandoned.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// The maximum size of the HTTP request head accepted by the loopback listener.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+/// How long [`LoginStrategy::Loopback`] waits for the browser to be redirected back.
+pub const LOGIN_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// The strategy used by [`login`] to present the authorization URL and capture the callback.
+pub enum LoginStrategy {
+    /// Open the authorization URL and capture the callback automatically by listening on the
+    /// host and port of the redirect URI. The redirect URI must be an ``http`` loopback address
+    /// such as ``http://localhost:8888/callback``. The login fails if no callback arrives within
+    /// [`LOGIN_TIMEOUT`].
+    Loopback,
+    /// Like [`Loopback`](Self::Loopback), failing if no callback arrives within the given duration.
+    LoopbackWithTimeout(Duration),
+    /// Open the authorization URL and read the callback URL pasted by the user from the given reader.
+    ///
+    /// Nothing is printed, callers without a browser present [`SpotifyAuth::authorize_url`] and
+    /// prompt for the callback URL themselves.
+    Paste(Box<dyn BufRead + Send>),
+    /// Open the authorization URL, printing it if no browser could be launched, and read the
+    /// callback URL pasted by the user from stdin after prompting for it.
+    Stdin,
+    /// Only print the authorization URL, never launching a browser, and read the callback URL
+    /// pasted by the user from stdin. Useful for headless hosts where the URL is opened on another machine.
+    Headless,
+}
+
+impl LoginStrategy {
+    /// Read the callback URL pasted by the user from stdin, see [`Stdin`](Self::Stdin).
+    pub fn stdin() -> Self {
+        LoginStrategy::Stdin
+    }
+}
+
+impl fmt::Debug for LoginStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoginStrategy::Loopback => f.write_str("Loopback"),
+            LoginStrategy::LoopbackWithTimeout(x) => {
+                f.debug_tuple("LoopbackWithTimeout").field(x).finish()
+            }
+            LoginStrategy::Paste(_) => f.debug_tuple("Paste").field(&"BufRead").finish(),
+            LoginStrategy::Stdin => f.write_str("Stdin"),
+            LoginStrategy::Headless => f.write_str("Headless"),
+        }
+    }
+}
+
+/// Run the full authorization flow and return the resulting token.
+///
+/// If a store is given the token is saved into it before being returned. The code is exchanged
+/// with the [`DefaultHttpClient`](crate::DefaultHttpClient), use [`login_with`] to provide a different HTTP client.
+///
+/// # Example
+///
+/// ```no_run
+/// # use spotify_oauth::{login, FileTokenStore, LoginStrategy, SpotifyAuth, SpotifyScope};
+/// # #[async_std::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+/// let auth = SpotifyAuth::new_from_env("code".into(), vec![SpotifyScope::Streaming], false);
+/// let store = FileTokenStore::new("token.json");
+///
+/// // Open the browser, wait for Spotify to redirect back and save the token.
+/// let token = login(&auth, LoginStrategy::Loopback, Some(&store)).await?;
+/// # Ok(()) }
+/// ```
+#[cfg(any(feature = "surf-client", feature = "reqwest-client"))]
+pub async fn login(
+    auth: &SpotifyAuth,
+    strategy: LoginStrategy,
+    store: Option<&dyn TokenStore>,
+) -> SpotifyResult<SpotifyToken> {
+    login_with(&crate::DefaultHttpClient::default(), auth, strategy, store).await
+}
+
+/// Run the full authorization flow using the given HTTP client and return the resulting token.
+///
+/// If a store is given the token is saved into it before being returned.
+pub async fn login_with<C: HttpClient + ?Sized>(
+    http: &C,
+    auth: &SpotifyAuth,
+    strategy: LoginStrategy,
+    store: Option<&dyn TokenStore>,
+) -> SpotifyResult<SpotifyToken> {
+    let auth_url = auth.authorize_url()?;
+
+    let callback = match strategy {
+        LoginStrategy::Loopback => capture(auth, &auth_url, LOGIN_TIMEOUT).await?,
+        LoginStrategy::LoopbackWithTimeout(timeout) => capture(auth, &auth_url, timeout).await?,
+        LoginStrategy::Paste(reader) => {
+            open_browser(&auth_url);
+            read_callback(reader).await?
+        }
+        LoginStrategy::Stdin => {
+            present_url(&auth_url, true);
+            prompt_callback().await?
+        }
+        LoginStrategy::Headless => {
+            present_url(&auth_url, false);
+            prompt_callback().await?
+        }
+    };
+
+    callback.validate_state(&auth.state)?;
+
+    let token = callback
+        .convert_into_token_with(
+            http,
+            auth.client_id.clone(),
+            auth.client_secret.clone(),
+            auth.redirect_uri.clone(),
+        )
+        .await?;
+
+    if let Some(store) = store {
+        store.save(&token)?;
+    }
+
+    Ok(token)
+}
+
+/// Present the authorization URL and capture the callback on the loopback address.
+async fn capture(
+    auth: &SpotifyAuth,
+    auth_url: &str,
+    timeout: Duration,
+) -> SpotifyResult<SpotifyCallback> {
+    // Bind before presenting the URL so the redirect can't arrive before we listen.
+    let listeners = bind_loopback(&auth.redirect_uri)?;
+    present_url(auth_url, true);
+    capture_loopback(
+        listeners,
+        auth.redirect_uri.clone(),
+        auth.state.clone(),
+        timeout,
+    )
+    .await
+}
+
+/// Open the URL in the default browser, falling back to printing it.
+fn present_url(url: &str, launch_browser: bool) {
+    if launch_browser && open_browser(url) {
+        return;
+    }
+
+    println!("Open the following URL to authorize the application:");
+    println!("{}", url);
+}
+
+#[cfg(feature = "browser")]
+fn open_browser(url: &str) -> bool {
+    open::that(url).is_ok()
+}
+
+#[cfg(not(feature = "browser"))]
+fn open_browser(_url: &str) -> bool {
+    false
+}
+
+/// Prompt for the callback URL and read it from stdin.
+async fn prompt_callback() -> SpotifyResult<SpotifyCallback> {
+    println!("Input callback URL:");
+    read_callback(Box::new(BufReader::new(io::stdin()))).await
+}
+
+/// Read a single callback URL from the reader on a separate thread.
+async fn read_callback(mut reader: Box<dyn BufRead + Send>) -> SpotifyResult<SpotifyCallback> {
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
+        let mut buffer = String::new();
+        let _ = tx.send(reader.read_line(&mut buffer).map(|_| buffer));
+    });
+
+    let buffer = rx
+        .await
+        .map_err(|_| SpotifyError::LoginFailure {
+            context: "Callback reader stopped unexpectedly.",
+        })?
+        .context(IoError)?;
+
+    SpotifyCallback::from_str(buffer.trim())
+}
+
+/// Bind a listener on every loopback address the host of the redirect URI resolves to.
+///
+/// ``localhost`` usually resolves to both ``127.0.0.1`` and ``::1``, and the browser may connect
+/// to either. Addresses which can't be bound, such as IPv6 on hosts without it, are skipped as
+/// long as one can.
+fn bind_loopback(redirect_uri: &Url) -> SpotifyResult<Vec<TcpListener>> {
+    let is_loopback = match redirect_uri.host() {
+        Some(Host::Domain(x)) => x == "localhost",
+        Some(Host::Ipv4(x)) => x.is_loopback(),
+        Some(Host::Ipv6(x)) => x.is_loopback(),
+        None => false,
+    };
+
+    if redirect_uri.scheme() != "http" || !is_loopback {
+        return Err(SpotifyError::LoginFailure {
+            context: "Loopback capture requires an http redirect URI on a loopback host.",
+        });
+    }
+
+    let mut listeners = Vec::new();
+    let mut error = None;
+    for addr in redirect_uri.socket_addrs(|| Some(80)).context(IoError)? {
+        match TcpListener::bind(addr) {
+            Ok(x) => listeners.push(x),
+            Err(e) => error = Some(e),
+        }
+    }
+
+    match error {
+        Some(e) if listeners.is_empty() => Err(e).context(IoError),
+        _ if listeners.is_empty() => Err(SpotifyError::LoginFailure {
+            context: "The redirect URI host did not resolve to any address.",
+        }),
+        _ => Ok(listeners),
+    }
+}
+
+/// Wait on a separate thread for the browser to be redirected to any of the listeners.
+///
+/// Requests for other paths, without callback parameters or with a state other than the given
+/// one are answered and ignored. Fails if no callback arrives before the timeout.
+async fn capture_loopback(
+    listeners: Vec<TcpListener>,
+    redirect_uri: Url,
+    state: String,
+    timeout: Duration,
+) -> SpotifyResult<SpotifyCallback> {
+    for listener in &listeners {
+        listener.set_nonblocking(true).context(IoError)?;
+    }
+
+    let started = Instant::now();
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || loop {
+        // Stop listening once the login future has been dropped.
+        if tx.is_canceled() {
+            return;
+        }
+
+        if started.elapsed() >= timeout {
+            let _ = tx.send(Err(SpotifyError::LoginFailure {
+                context: "Timed out waiting for the authorization callback.",
+            }));
+            return;
+        }
+
+        let mut idle = true;
+        for listener in &listeners {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    idle = false;
+                    if let Some(callback) = handle_connection(stream, &redirect_uri, &state) {
+                        let _ = tx.send(Ok(callback));
+                        return;
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    let _ = tx.send(Err(e).context(IoError));
+                    return;
+                }
+            }
+        }
+
+        if idle {
+            thread::sleep(POLL_INTERVAL);
+        }
+    });
+
+    rx.await.map_err(|_| SpotifyError::LoginFailure {
+        context: "Loopback listener stopped unexpectedly.",
+    })?
+}
+
+/// Answer a single request on the loopback listener, returning the callback if it carried one
+/// with the expected state.
+fn handle_connection(
+    mut stream: TcpStream,
+    redirect_uri: &Url,
+    state: &str,
+) -> Option<SpotifyCallback> {
+    // Accepted sockets inherit non-blocking mode on some platforms.
+    stream.set_nonblocking(false).ok()?;
+    stream.set_read_timeout(Some(Duration::from_secs(5))).ok()?;
+
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|x| x == b"\r\n\r\n") && buf.len() < MAX_REQUEST_SIZE {
+        match stream.read(&mut chunk) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+
+    let request = String::from_utf8_lossy(&buf);
+    let url = match parse_request_target(&request).and_then(|x| redirect_uri.join(x).ok()) {
+        Some(x) if x.path() == redirect_uri.path() => x,
+        _ => {
+            respond(&mut stream, "404 Not Found", "Not found.");
+            return None;
+        }
+    };
+
+    let callback = match SpotifyCallback::from_str(url.as_str()) {
+        Ok(x) => x,
+        Err(_) => {
+            respond(&mut stream, "400 Bad Request", "Invalid callback.");
+            return None;
+        }
+    };
+
+    // A callback for another authorization request must not end this login.
+    if callback.validate_state(state).is_err() {
+        respond(
+            &mut stream,
+            "400 Bad Request",
+            "The authorization request could not be verified.",
+        );
+        return None;
+    }
+
+    if callback.error.is_some() {
+        respond(
+            &mut stream,
+            "403 Forbidden",
+            "Authorization was not granted. You may close this window.",
+        );
+    } else {
+        respond(
+            &mut stream,
+            "200 OK",
+            "Authorization complete. You may close this window.",
+        );
+    }
+
+    Some(callback)
+}
+
+/// Extract the request target from the request line of a ``GET`` request.
+fn parse_request_target(request: &str) -> Option<&str> {
+    let mut parts = request.lines().next()?.split_whitespace();
+
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some(target)) => Some(target),
+        _ => None,
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) {
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FileTokenStore, HttpResponse, ScriptedHttpClient};
+    use futures::executor::block_on;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_parse_request_target() {
+        assert_eq!(
+            parse_request_target("GET /callback?code=a&state=b HTTP/1.1\r\nHost: localhost\r\n"),
+            Some("/callback?code=a&state=b")
+        );
+        assert_eq!(parse_request_target("POST /callback HTTP/1.1\r\n"), None);
+        assert_eq!(parse_request_target(""), None);
+    }
+
+    #[test]
+    fn test_bind_rejects_remote_redirect() {
+        let url = Url::parse("https://example.com/callback").unwrap();
+
+        assert_eq!(
+            bind_loopback(&url).unwrap_err().to_string(),
+            "Login failure: Loopback capture requires an http redirect URI on a loopback host."
+        );
+    }
+
+    #[test]
+    fn test_bind_every_resolved_address() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let redirect_uri = Url::parse(&format!("http://localhost:{}/callback", port)).unwrap();
+        let resolved = redirect_uri.socket_addrs(|| None).unwrap();
+
+        // Whichever address the browser picks, a listener is waiting on it.
+        let listeners = bind_loopback(&redirect_uri).unwrap();
+        for listener in &listeners {
+            assert!(resolved.contains(&listener.local_addr().unwrap()));
+        }
+        assert!(TcpStream::connect(listeners[0].local_addr().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_read_pasted_callback() {
+        let reader = Cursor::new("  http://localhost:8888/callback?code=AQD0yXvFEOvw&state=sN\n");
+
+        assert_eq!(
+            block_on(read_callback(Box::new(reader))).unwrap(),
+            SpotifyCallback::new(Some("AQD0yXvFEOvw".to_string()), None, "sN".to_string())
+        );
+        assert_eq!(
+            format!("{:?}", LoginStrategy::Paste(Box::new(io::empty()))),
+            "Paste(\"BufRead\")"
+        );
+    }
+
+    #[test]
+    fn test_login_with_pasted_callback() {
+        let auth = SpotifyAuth::new(
+            "00000000000".into(),
+            "secret".into(),
+            "code".into(),
+            "http://localhost:8888/callback".into(),
+            vec![],
+            false,
+        );
+        let http = ScriptedHttpClient::new();
+        http.push_response(HttpResponse::new(
+            200,
+            r#"{"access_token":"NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw","token_type":"Bearer","scope":"","expires_in":3600,"refresh_token":"NgAagAHfVxDkSvCUm_SHo"}"#,
+        ));
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileTokenStore::new(dir.path().join("token.json"));
+
+        // A callback for another authorization request is rejected before any exchange.
+        let forged = Cursor::new("http://localhost:8888/callback?code=AQD0yXvFEOvw&state=forged\n");
+        assert!(block_on(login_with(
+            &http,
+            &auth,
+            LoginStrategy::Paste(Box::new(forged)),
+            Some(&store)
+        ))
+        .is_err());
+        assert!(http.requests().is_empty());
+
+        let pasted = Cursor::new(format!(
+            "http://localhost:8888/callback?code=AQD0yXvFEOvw&state={}\n",
+            auth.state
+        ));
+        let token = block_on(login_with(
+            &http,
+            &auth,
+            LoginStrategy::Paste(Box::new(pasted)),
+            Some(&store),
+        ))
+        .unwrap();
+
+        assert_eq!(http.requests().len(), 1);
+        assert_eq!(store.load().unwrap(), Some(token));
+    }
+
+    /// Send the requests to the listener one after another, returning the response statuses.
+    fn browse(addr: std::net::SocketAddr, targets: &'static [&'static str]) -> Vec<String> {
+        let mut statuses = Vec::new();
+        for target in targets {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, addr).unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            statuses.push(response.lines().next().unwrap().to_string());
+        }
+        statuses
+    }
+
+    #[test]
+    fn test_loopback_capture() {
+        let redirect_uri = Url::parse("http://127.0.0.1:0/callback").unwrap();
+        let listeners = bind_loopback(&redirect_uri).unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+
+        let browser = thread::spawn(move || {
+            browse(
+                addr,
+                &[
+                    "/favicon.ico",
+                    "/callback?code=AQD0yXvFEOvw&state=forged",
+                    "/callback?code=AQD0yXvFEOvw&state=sN",
+                ],
+            )
+        });
+
+        assert_eq!(
+            block_on(capture_loopback(
+                listeners,
+                redirect_uri,
+                "sN".into(),
+                LOGIN_TIMEOUT
+            ))
+            .unwrap(),
+            SpotifyCallback::new(Some("AQD0yXvFEOvw".to_string()), None, "sN".to_string())
+        );
+        assert_eq!(
+            browser.join().unwrap(),
+            vec![
+                "HTTP/1.1 404 Not Found",
+                "HTTP/1.1 400 Bad Request",
+                "HTTP/1.1 200 OK"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_loopback_denied_and_timeout() {
+        let redirect_uri = Url::parse("http://127.0.0.1:0/callback").unwrap();
+        let listeners = bind_loopback(&redirect_uri).unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+
+        let browser =
+            thread::spawn(move || browse(addr, &["/callback?error=access_denied&state=sN"]));
+
+        let callback = block_on(capture_loopback(
+            listeners,
+            redirect_uri.clone(),
+            "sN".into(),
+            LOGIN_TIMEOUT,
+        ))
+        .unwrap();
+        assert_eq!(
+            callback.authorization_code().unwrap_err().code(),
+            "access_denied"
+        );
+        assert_eq!(browser.join().unwrap(), vec!["HTTP/1.1 403 Forbidden"]);
+
+        // Nothing is ever redirected to this listener.
+        let listeners = bind_loopback(&redirect_uri).unwrap();
+        let error = block_on(capture_loopback(
+            listeners,
+            redirect_uri,
+            "sN".into(),
+            Duration::from_millis(100),
+        ))
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Login failure: Timed out waiting for the authorization callback."
+        );
+    }
+}
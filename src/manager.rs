@@ -0,0 +1,607 @@
+//! Automatic refreshing of a Spotify Token.
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::lock::Mutex;
+use futures::stream::Stream;
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::*;
+use crate::grant::Grant;
+use crate::http::HttpClient;
+use crate::retry::RetryPolicy;
+use crate::store::{TokenLock, TokenStore};
+use crate::{SecretString, SpotifyToken, SPOTIFY_TOKEN_URL};
+
+#[cfg(any(feature = "runtime-tokio", feature = "runtime-async-std"))]
+pub(crate) use self::refresher::spawn_task;
+
+/// How often a refresh waiting for another process to release the store checks the lock again.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long before expiry a token is refreshed by default.
+const DEFAULT_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// A callback notified of a refreshed token.
+type TokenHook = Box<dyn Fn(&SpotifyToken) + Send + Sync>;
+/// A callback notified of a failed refresh.
+type ErrorHook = Box<dyn Fn(&SpotifyError) + Send + Sync>;
+
+/// Keeps a Spotify Token valid by refreshing it before it expires.
+///
+/// Refreshed tokens are saved into the store given with [`with_store`](Self::with_store).
+/// With the ``runtime-tokio`` or ``runtime-async-std`` feature the token can also be refreshed
+/// in the background using [`spawn_refresher`](Self::spawn_refresher).
+///
+/// Components which need the new access token as soon as it is refreshed can
+/// [`subscribe`](Self::subscribe) to a stream of refreshed tokens, or register callbacks with
+/// [`on_refreshed`](Self::on_refreshed), [`on_refresh_failed`](Self::on_refresh_failed) and
+/// [`on_reauthorization_required`](Self::on_reauthorization_required).
+///
+/// # Example
+///
+/// ```no_run
+/// # use spotify_oauth::{FileTokenStore, SurfClient, TokenManager, TokenStore};
+/// # #[async_std::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+/// let store = FileTokenStore::new("token.json");
+/// let token = store.load()?.unwrap();
+///
+/// let manager = TokenManager::new(SurfClient::default(), "00000000000".into(), "secret".into(), token)
+///     .with_store(store);
+///
+/// // The token is refreshed first if it is about to expire.
+/// let access_token = manager.access_token().await?;
+/// # Ok(()) }
+/// ```
+pub struct TokenManager<C> {
+    http: C,
+    client_id: String,
+    client_secret: SecretString,
+    token: Mutex<SpotifyToken>,
+    store: Option<Box<dyn TokenStore>>,
+    refresh_margin: Duration,
+    retry: RetryPolicy,
+    clock: Arc<dyn Clock>,
+    subscribers: Subscribers,
+    refreshed_hooks: Vec<TokenHook>,
+    failed_hooks: Vec<ErrorHook>,
+    reauthorization_hooks: Vec<ErrorHook>,
+}
+
+impl<C: HttpClient> TokenManager<C> {
+    /// Create a new Token Manager which refreshes the token with the given HTTP client and application credentials.
+    pub fn new(
+        http: C,
+        client_id: String,
+        client_secret: SecretString,
+        token: SpotifyToken,
+    ) -> Self {
+        Self {
+            http,
+            client_id,
+            client_secret,
+            token: Mutex::new(token),
+            store: None,
+            refresh_margin: DEFAULT_REFRESH_MARGIN,
+            retry: RetryPolicy::default(),
+            clock: Arc::new(SystemClock),
+            subscribers: Subscribers::default(),
+            refreshed_hooks: Vec::new(),
+            failed_hooks: Vec::new(),
+            reauthorization_hooks: Vec::new(),
+        }
+    }
+
+    /// Save every refreshed token into the given store.
+    pub fn with_store<S: TokenStore + 'static>(mut self, store: S) -> Self {
+        self.store = Some(Box::new(store));
+        self
+    }
+
+    /// Refresh the token when it expires within the given margin, defaults to 60 seconds.
+    pub fn with_refresh_margin(mut self, refresh_margin: Duration) -> Self {
+        self.refresh_margin = refresh_margin;
+        self
+    }
+
+    /// Retry failed refreshes according to the given policy, defaults to [`RetryPolicy::default`].
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Check expiry and schedule refreshes with the given clock, defaults to [`SystemClock`].
+    pub fn with_clock<K: Clock + 'static>(mut self, clock: K) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Call the given function with every refreshed token, after it has been saved.
+    pub fn on_refreshed<F: Fn(&SpotifyToken) + Send + Sync + 'static>(mut self, hook: F) -> Self {
+        self.refreshed_hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Call the given function with the error of every failed refresh.
+    pub fn on_refresh_failed<F: Fn(&SpotifyError) + Send + Sync + 'static>(
+        mut self,
+        hook: F,
+    ) -> Self {
+        self.failed_hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Call the given function when the refresh token is rejected, so the user has to authorize
+    /// the application again. The refresh failed hooks are called as well.
+    pub fn on_reauthorization_required<F: Fn(&SpotifyError) + Send + Sync + 'static>(
+        mut self,
+        hook: F,
+    ) -> Self {
+        self.reauthorization_hooks.push(Box::new(hook));
+        self
+    }
+
+    /// A stream of every token refreshed from now on.
+    ///
+    /// The stream ends when the manager is dropped. Dropping the stream unsubscribes.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use futures::StreamExt;
+    /// # use spotify_oauth::{FileTokenStore, SurfClient, TokenManager, TokenStore};
+    /// # #[async_std::main]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+    /// # let token = FileTokenStore::new("token.json").load()?.unwrap();
+    /// let manager = TokenManager::new(SurfClient::default(), "00000000000".into(), "secret".into(), token);
+    /// let mut updates = manager.subscribe();
+    ///
+    /// while let Some(token) = updates.next().await {
+    ///     // Reconnect with the new access token.
+    /// }
+    /// # Ok(()) }
+    /// ```
+    pub fn subscribe(&self) -> TokenUpdates {
+        self.subscribers.subscribe()
+    }
+
+    /// The current token, without refreshing it.
+    pub async fn current(&self) -> SpotifyToken {
+        self.token.lock().await.clone()
+    }
+
+    /// A valid token, refreshing it first if it is about to expire.
+    pub async fn token(&self) -> SpotifyResult<SpotifyToken> {
+        let mut token = self.token.lock().await;
+
+        if self.until_refresh(&token) == Duration::from_secs(0) {
+            *token = self.refresh_locked(&token).await?;
+        }
+
+        Ok(token.clone())
+    }
+
+    /// A valid access token, refreshing the token first if it is about to expire.
+    pub async fn access_token(&self) -> SpotifyResult<SecretString> {
+        Ok(self.token().await?.access_token)
+    }
+
+    /// Refresh the token now, whether it is about to expire or not.
+    pub async fn refresh(&self) -> SpotifyResult<SpotifyToken> {
+        let mut token = self.token.lock().await;
+        *token = self.refresh_locked(&token).await?;
+
+        Ok(token.clone())
+    }
+
+    /// Refresh the token while holding the lock, so concurrent callers share a single refresh.
+    async fn refresh_locked(&self, token: &SpotifyToken) -> SpotifyResult<SpotifyToken> {
+        let result = self.request_refresh(token).await;
+
+        match result {
+            Ok(ref refreshed) => {
+                self.subscribers.notify(refreshed);
+                self.refreshed_hooks.iter().for_each(|x| x(refreshed));
+            }
+            Err(ref e) => {
+                self.failed_hooks.iter().for_each(|x| x(e));
+                if e.requires_reauthorization() {
+                    self.reauthorization_hooks.iter().for_each(|x| x(e));
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Request a new token and save it into the store.
+    ///
+    /// The store is locked for the duration of the request. If another process refreshed the
+    /// token in the meantime, its token is used instead, and refreshed with its refresh token if
+    /// it is about to expire as well, since the refresh token held in memory may have been
+    /// rotated away.
+    async fn request_refresh(&self, token: &SpotifyToken) -> SpotifyResult<SpotifyToken> {
+        token.verify_client(&self.client_id)?;
+
+        let _lock = match self.store {
+            Some(ref store) => Some(lock_store(&**store).await?),
+            None => None,
+        };
+
+        let mut token = token;
+        let stored = match self.store {
+            Some(ref store) => store.load()?,
+            None => None,
+        };
+        if let Some(ref stored) = stored {
+            if stored.access_token != token.access_token {
+                stored.verify_client(&self.client_id)?;
+                if self.until_refresh(stored) > Duration::from_secs(0) {
+                    return Ok(stored.clone());
+                }
+                token = stored;
+            }
+        }
+
+        let refreshed = Grant::RefreshToken {
+            refresh_token: token.refresh_token.expose_secret(),
+        }
+        .execute(
+            &self.http,
+            SPOTIFY_TOKEN_URL,
+            &self.client_id,
+            self.client_secret.expose_secret(),
+            &self.retry,
+            &*self.clock,
+        )
+        .await?;
+
+        if let Some(ref store) = self.store {
+            store.save(&refreshed)?;
+        }
+
+        Ok(refreshed)
+    }
+
+    /// How long until the token should be refreshed, zero if it should be refreshed now.
+    ///
+    /// Tokens with an unknown expiry are always refreshed.
+    fn until_refresh(&self, token: &SpotifyToken) -> Duration {
+        token
+            .lifetime(&*self.clock)
+            .with_clock_skew(self.refresh_margin)
+            .remaining()
+    }
+}
+
+/// Lock the store, waiting without blocking the executor while another process holds the lock.
+async fn lock_store(store: &dyn TokenStore) -> SpotifyResult<TokenLock> {
+    loop {
+        if let Some(lock) = store.try_lock()? {
+            return Ok(lock);
+        }
+
+        crate::rt::sleep(LOCK_POLL_INTERVAL).await;
+    }
+}
+
+/// The senders of the [`TokenUpdates`] streams handed out to subscribers.
+#[derive(Debug, Default)]
+pub(crate) struct Subscribers {
+    senders: std::sync::Mutex<Vec<UnboundedSender<SpotifyToken>>>,
+}
+
+impl Subscribers {
+    /// A new stream receiving every token given to [`notify`](Self::notify) from now on.
+    pub(crate) fn subscribe(&self) -> TokenUpdates {
+        let (tx, rx) = mpsc::unbounded();
+        self.senders
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(tx);
+
+        TokenUpdates { rx }
+    }
+
+    /// Send the token to every subscriber, forgetting those which dropped their stream.
+    pub(crate) fn notify(&self, token: &SpotifyToken) {
+        self.senders
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|x| x.unbounded_send(token.clone()).is_ok());
+    }
+}
+
+/// A stream of updated tokens, see [`TokenManager::subscribe`].
+#[derive(Debug)]
+pub struct TokenUpdates {
+    rx: UnboundedReceiver<SpotifyToken>,
+}
+
+impl Stream for TokenUpdates {
+    type Item = SpotifyToken;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+#[cfg(any(feature = "runtime-tokio", feature = "runtime-async-std"))]
+pub use self::refresher::RefresherHandle;
+
+#[cfg(any(feature = "runtime-tokio", feature = "runtime-async-std"))]
+mod refresher {
+    use futures::future::{abortable, AbortHandle};
+
+    use std::future::Future;
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::TokenManager;
+    use crate::http::HttpClient;
+    use crate::rt;
+
+    /// How long to wait before trying again after a failed background refresh.
+    const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+    /// Handle to a background refresher, which stops the refresher when dropped.
+    #[derive(Debug)]
+    pub struct RefresherHandle {
+        abort: AbortHandle,
+    }
+
+    impl RefresherHandle {
+        /// Stop the background refresher.
+        pub fn stop(self) {}
+    }
+
+    impl Drop for RefresherHandle {
+        fn drop(&mut self) {
+            self.abort.abort();
+        }
+    }
+
+    impl<C: HttpClient + 'static> TokenManager<C> {
+        /// Refresh the token in the background on the enabled runtime, shortly before it expires.
+        ///
+        /// The refresher runs until the returned handle is dropped or stopped. Failed refreshes are
+        /// retried every 30 seconds, except when the refresh token was rejected: the refresher then
+        /// stops, as the user has to authorize the application again, which is reported to the
+        /// [`on_reauthorization_required`](TokenManager::on_reauthorization_required) hooks.
+        pub fn spawn_refresher(self: &Arc<Self>) -> RefresherHandle {
+            let manager = Arc::clone(self);
+            spawn_task(async move {
+                loop {
+                    let delay = manager.until_refresh(&manager.current().await);
+                    rt::sleep(delay).await;
+
+                    match manager.token().await {
+                        Ok(_) => {}
+                        Err(ref e) if e.requires_reauthorization() => break,
+                        Err(_) => rt::sleep(RETRY_INTERVAL).await,
+                    }
+                }
+            })
+        }
+    }
+
+    /// Spawn the task on the enabled runtime, running until the returned handle is dropped.
+    pub(crate) fn spawn_task<F: Future<Output = ()> + Send + 'static>(task: F) -> RefresherHandle {
+        let (task, abort) = abortable(task);
+
+        rt::spawn(async move {
+            let _ = task.await;
+        });
+
+        RefresherHandle { abort }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HttpResponse, ManualClock, ScriptedHttpClient};
+    use chrono::{TimeZone, Utc};
+    use futures::executor::block_on;
+
+    /// The time the clock of the tests starts at.
+    const START: i64 = 1_577_836_800;
+
+    fn clock() -> ManualClock {
+        ManualClock::new(Utc.timestamp_opt(START, 0).unwrap())
+    }
+
+    fn token(expires_at: Option<i64>) -> SpotifyToken {
+        SpotifyToken {
+            access_token: "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw".into(),
+            token_type: "Bearer".to_string(),
+            scope: vec![],
+            expires_in: 3600,
+            expires_at,
+            issued_at: None,
+            refresh_token: "NgAagAHfVxDkSvCUm_SHo".into(),
+            client_id: None,
+        }
+    }
+
+    #[test]
+    fn test_until_refresh() {
+        let clock = clock();
+        let manager = TokenManager::new(
+            ScriptedHttpClient::new(),
+            "id".into(),
+            "secret".into(),
+            token(None),
+        )
+        .with_clock(clock.clone());
+
+        assert_eq!(manager.until_refresh(&token(None)), Duration::from_secs(0));
+        assert_eq!(
+            manager.until_refresh(&token(Some(START + 30))),
+            Duration::from_secs(0)
+        );
+        assert_eq!(
+            manager.until_refresh(&token(Some(START + 3600))),
+            Duration::from_secs(3540)
+        );
+
+        clock.advance(Duration::from_secs(3550));
+        assert_eq!(
+            manager.until_refresh(&token(Some(START + 3600))),
+            Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn test_token_refreshes_when_expiring() {
+        let http = ScriptedHttpClient::new();
+        http.push_response(HttpResponse::new(
+            200,
+            r#"{"access_token":"NgA6ZcYIixn8bU","token_type":"Bearer","expires_in":3600}"#,
+        ));
+
+        let clock = clock();
+        let dir = tempfile::tempdir().unwrap();
+        let store = crate::FileTokenStore::new(dir.path().join("token.json"));
+        let manager = TokenManager::new(
+            &http,
+            "id".into(),
+            "secret".into(),
+            token(Some(START + 3600)),
+        )
+        .with_store(store.clone())
+        .with_clock(clock.clone());
+
+        // The token is used as is until it is about to expire.
+        assert_eq!(
+            block_on(manager.access_token()).unwrap().expose_secret(),
+            "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw"
+        );
+        assert!(http.requests().is_empty());
+
+        // The first call after that refreshes the token, the second reuses it.
+        clock.advance(Duration::from_secs(3590));
+        assert_eq!(
+            block_on(manager.access_token()).unwrap().expose_secret(),
+            "NgA6ZcYIixn8bU"
+        );
+        assert_eq!(
+            block_on(manager.access_token()).unwrap().expose_secret(),
+            "NgA6ZcYIixn8bU"
+        );
+        assert_eq!(http.requests().len(), 1);
+
+        let refreshed = block_on(manager.current());
+        assert_eq!(refreshed.expires_at, Some(START + 3590 + 3600));
+        assert_eq!(store.load().unwrap(), Some(refreshed));
+    }
+
+    #[test]
+    fn test_refresh_uses_token_refreshed_elsewhere() {
+        let http = ScriptedHttpClient::new();
+        let dir = tempfile::tempdir().unwrap();
+        let store = crate::FileTokenStore::new(dir.path().join("token.json"));
+
+        // Another process has already refreshed the token and rotated the refresh token.
+        let mut stored = token(Some(START + 3600));
+        stored.access_token = "NgA6ZcYIixn8bU".into();
+        stored.refresh_token = "NgBnV3OeKoZUXk".into();
+        store.save(&stored).unwrap();
+
+        // Which still holds the lock for a moment, so the refresh has to wait for it.
+        let held = store.try_lock().unwrap().unwrap();
+        let release = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            drop(held);
+        });
+
+        let manager = TokenManager::new(&http, "id".into(), "secret".into(), token(None))
+            .with_store(store)
+            .with_clock(clock());
+
+        assert_eq!(block_on(manager.token()).unwrap(), stored);
+        assert!(http.requests().is_empty());
+        release.join().unwrap();
+    }
+
+    #[test]
+    fn test_refresh_with_rotated_token_near_expiry() {
+        let http = ScriptedHttpClient::new();
+        http.push_response(HttpResponse::new(
+            200,
+            r#"{"access_token":"NgDf4lR0bG","token_type":"Bearer","expires_in":3600}"#,
+        ));
+        let dir = tempfile::tempdir().unwrap();
+        let store = crate::FileTokenStore::new(dir.path().join("token.json"));
+
+        // Another process refreshed and rotated the token, which is about to expire again.
+        let mut stored = token(Some(START + 10));
+        stored.access_token = "NgA6ZcYIixn8bU".into();
+        stored.refresh_token = "NgBnV3OeKoZUXk".into();
+        store.save(&stored).unwrap();
+
+        let manager = TokenManager::new(&http, "id".into(), "secret".into(), token(None))
+            .with_store(store)
+            .with_clock(clock());
+
+        let refreshed = block_on(manager.token()).unwrap();
+        assert_eq!(refreshed.access_token.expose_secret(), "NgDf4lR0bG");
+        assert_eq!(refreshed.refresh_token.expose_secret(), "NgBnV3OeKoZUXk");
+
+        // The rotated refresh token is sent, not the one held in memory.
+        let requests = http.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].body.ends_with(b"refresh_token=NgBnV3OeKoZUXk"));
+    }
+
+    #[test]
+    fn test_refresh_notifications() {
+        use futures::StreamExt;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let http = ScriptedHttpClient::new();
+        http.push_response(HttpResponse::new(
+            200,
+            r#"{"access_token":"NgA6ZcYIixn8bU","token_type":"Bearer","expires_in":3600}"#,
+        ));
+        http.push_response(HttpResponse::new(400, r#"{"error":"invalid_grant"}"#));
+
+        let refreshed = Arc::new(AtomicUsize::new(0));
+        let failed = Arc::new(AtomicUsize::new(0));
+        let reauthorization = Arc::new(AtomicUsize::new(0));
+        let (x, y, z) = (refreshed.clone(), failed.clone(), reauthorization.clone());
+
+        let manager = TokenManager::new(&http, "id".into(), "secret".into(), token(None))
+            .with_clock(clock())
+            .on_refreshed(move |_| {
+                x.fetch_add(1, Ordering::SeqCst);
+            })
+            .on_refresh_failed(move |_| {
+                y.fetch_add(1, Ordering::SeqCst);
+            })
+            .on_reauthorization_required(move |_| {
+                z.fetch_add(1, Ordering::SeqCst);
+            });
+        let mut updates = manager.subscribe();
+
+        block_on(manager.refresh()).unwrap();
+        let update = block_on(updates.next()).unwrap();
+        assert_eq!(update.access_token.expose_secret(), "NgA6ZcYIixn8bU");
+        assert_eq!(refreshed.load(Ordering::SeqCst), 1);
+
+        // A rejected refresh token requires the user to authorize again.
+        assert!(block_on(manager.refresh()).is_err());
+        assert_eq!(failed.load(Ordering::SeqCst), 1);
+        assert_eq!(reauthorization.load(Ordering::SeqCst), 1);
+
+        // The stream ends once the manager is gone.
+        drop(manager);
+        assert!(block_on(updates.next()).is_none());
+    }
+}
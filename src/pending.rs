@@ -0,0 +1,243 @@
+//! Authorization requests waiting for their callback.
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::*;
+use crate::secret::SecretString;
+use crate::SpotifyScope;
+
+/// How long an authorization request waits for its callback by default.
+const DEFAULT_TTL: Duration = Duration::from_secs(10 * 60);
+/// The number of authorization requests kept in memory by default.
+const DEFAULT_CAPACITY: usize = 10_000;
+
+/// An authorization request waiting for its callback.
+///
+/// Holds the parameters the authorization code must be exchanged with, so a server handling many
+/// logins at once can find them again from the state of the callback.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingAuthorization {
+    /// The state sent with the authorization request.
+    pub state: String,
+    /// The redirect URI sent with the authorization request.
+    pub redirect_uri: Url,
+    /// The scopes requested.
+    pub scope: Vec<SpotifyScope>,
+    /// The PKCE code verifier, sent with the code exchange if present.
+    #[serde(default)]
+    pub code_verifier: Option<SecretString>,
+    /// The local path to return the user to after the authorization.
+    #[serde(default)]
+    pub return_path: Option<String>,
+    /// The unix timestamp at which the request was made.
+    pub created_at: i64,
+}
+
+impl PendingAuthorization {
+    /// Create a new pending authorization made at the current time of the clock.
+    pub fn new(
+        state: String,
+        redirect_uri: Url,
+        scope: Vec<SpotifyScope>,
+        clock: &dyn Clock,
+    ) -> Self {
+        Self {
+            state,
+            redirect_uri,
+            scope,
+            code_verifier: None,
+            return_path: None,
+            created_at: clock.now().timestamp(),
+        }
+    }
+
+    /// Send the given PKCE code verifier with the code exchange.
+    pub fn with_code_verifier(mut self, code_verifier: SecretString) -> Self {
+        self.code_verifier = Some(code_verifier);
+        self
+    }
+
+    /// Return the user to the given local path after the authorization.
+    pub fn with_return_path(mut self, return_path: String) -> Self {
+        self.return_path = Some(return_path);
+        self
+    }
+
+    /// Whether the request is older than the given time to live, according to the given clock.
+    pub fn is_expired(&self, clock: &dyn Clock, ttl: Duration) -> bool {
+        clock.now().timestamp() - self.created_at > ttl.as_secs() as i64
+    }
+}
+
+/// Storage backend for authorization requests waiting for their callback.
+///
+/// Every entry is consumed at most once: [`take`](Self::take) removes the entry, so a callback
+/// can't be completed twice. Implementations should not return entries which have expired.
+pub trait PendingAuthorizationStore: Send + Sync {
+    /// Store the given authorization request under its state.
+    fn insert(&self, pending: PendingAuthorization) -> SpotifyResult<()>;
+
+    /// Remove and return the authorization request with the given state, ``None`` if there is no
+    /// such request or it has expired.
+    fn take(&self, state: &str) -> SpotifyResult<Option<PendingAuthorization>>;
+}
+
+/// A Pending Authorization Store keeping the requests in memory.
+///
+/// Expired requests are removed when new requests are inserted. Once the store holds as many
+/// requests as its capacity, new requests are rejected until older ones complete or expire.
+///
+/// # Example
+///
+/// ```
+/// # use spotify_oauth::{MemoryPendingStore, PendingAuthorization, PendingAuthorizationStore, SpotifyScope, SystemClock};
+/// # use url::Url;
+/// let store = MemoryPendingStore::new();
+/// let redirect_uri = Url::parse("http://localhost:8000/callback").unwrap();
+///
+/// store.insert(PendingAuthorization::new("sN".into(), redirect_uri, vec![SpotifyScope::Streaming], &SystemClock)).unwrap();
+///
+/// // The request can only be taken once.
+/// assert!(store.take("sN").unwrap().is_some());
+/// assert!(store.take("sN").unwrap().is_none());
+/// ```
+#[derive(Debug)]
+pub struct MemoryPendingStore {
+    entries: Mutex<HashMap<String, PendingAuthorization>>,
+    ttl: Duration,
+    capacity: usize,
+    clock: Arc<dyn Clock>,
+}
+
+impl MemoryPendingStore {
+    /// Create a new store keeping up to 10000 requests for 10 minutes each.
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            ttl: DEFAULT_TTL,
+            capacity: DEFAULT_CAPACITY,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Expire requests older than the given duration.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Keep at most the given number of requests.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Expire requests with the given clock, defaults to [`SystemClock`].
+    pub fn with_clock<K: Clock + 'static>(mut self, clock: K) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The number of requests in the store, including expired ones not yet removed.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Whether the store holds no requests.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Implementation of Default for MemoryPendingStore.
+///
+/// Equivalent to [`MemoryPendingStore::new`].
+impl Default for MemoryPendingStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PendingAuthorizationStore for MemoryPendingStore {
+    fn insert(&self, pending: PendingAuthorization) -> SpotifyResult<()> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+
+        if entries.len() >= self.capacity {
+            entries.retain(|_, x| !x.is_expired(&*self.clock, self.ttl));
+        }
+
+        if entries.len() >= self.capacity && !entries.contains_key(&pending.state) {
+            return CapacityExceeded {
+                context: "Too many pending authorization requests.",
+            }
+            .fail();
+        }
+
+        entries.insert(pending.state.clone(), pending);
+        Ok(())
+    }
+
+    fn take(&self, state: &str) -> SpotifyResult<Option<PendingAuthorization>> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+
+        Ok(entries
+            .remove(state)
+            .filter(|x| !x.is_expired(&*self.clock, self.ttl)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn pending(clock: &ManualClock, state: &str) -> PendingAuthorization {
+        let redirect_uri = Url::parse("http://localhost:8888/callback").unwrap();
+        PendingAuthorization::new(
+            state.into(),
+            redirect_uri,
+            vec![SpotifyScope::Streaming],
+            clock,
+        )
+    }
+
+    #[test]
+    fn test_take_once_and_expire() {
+        let clock = ManualClock::new(chrono::Utc::now());
+        let store = MemoryPendingStore::new().with_clock(clock.clone());
+        store.insert(pending(&clock, "a")).unwrap();
+        assert_eq!(store.take("a").unwrap().unwrap().state, "a");
+        assert_eq!(store.take("a").unwrap(), None);
+
+        store.insert(pending(&clock, "b")).unwrap();
+        clock.advance(Duration::from_secs(11 * 60));
+        assert_eq!(store.take("b").unwrap(), None);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let clock = ManualClock::new(chrono::Utc::now());
+        let store = MemoryPendingStore::new()
+            .with_capacity(2)
+            .with_ttl(Duration::from_secs(60))
+            .with_clock(clock.clone());
+        store.insert(pending(&clock, "a")).unwrap();
+        clock.advance(Duration::from_secs(30));
+        store.insert(pending(&clock, "b")).unwrap();
+
+        let error = store.insert(pending(&clock, "c")).unwrap_err();
+        assert_eq!(error.code(), "capacity_exceeded");
+
+        // Expired requests make room for new ones.
+        clock.advance(Duration::from_secs(40));
+        store.insert(pending(&clock, "c")).unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(store.take("a").unwrap().is_none());
+    }
+}
@@ -0,0 +1,175 @@
+//! Conversion of errors into HTTP responses for web integrations.
+
+use serde::Serialize;
+
+use crate::error::SpotifyError;
+
+/// The content type of the problem details body.
+pub const PROBLEM_CONTENT_TYPE: &str = "application/problem+json";
+
+/// Problem Details for HTTP APIs
+///
+/// A response describing a failed authorization, following [RFC 7807](https://tools.ietf.org/html/rfc7807).
+/// Only the error code and a generic message are exposed, never the underlying error, response
+/// bodies or credentials.
+///
+/// # Example
+///
+/// ```
+/// # use spotify_oauth::{Problem, SpotifyCallback};
+/// // The callback does not belong to an authorization request made by this server.
+/// let callback = SpotifyCallback::new(Some("NApCCgBkWtQ".to_string()), None, String::from("forged"));
+/// let error = callback.validate_state("test").unwrap_err();
+///
+/// let problem = Problem::from(&error);
+/// assert_eq!(problem.status, 400);
+/// assert_eq!(problem.code, "state_mismatch");
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Problem {
+    /// A URI identifying the kind of problem.
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    /// The reason phrase of the HTTP status.
+    pub title: &'static str,
+    /// The HTTP status code to respond with.
+    pub status: u16,
+    /// A message safe to show to the user.
+    pub detail: &'static str,
+    /// The stable error code, see [`SpotifyError::code`].
+    pub code: &'static str,
+}
+
+impl Problem {
+    /// The problem details as a JSON body, to be sent with the [`PROBLEM_CONTENT_TYPE`] content type.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+impl From<&SpotifyError> for Problem {
+    fn from(error: &SpotifyError) -> Self {
+        let status = error.http_status();
+
+        Self {
+            problem_type: format!("urn:spotify-oauth:error:{}", error.code()),
+            title: reason_phrase(status),
+            status,
+            detail: error.user_message(),
+            code: error.code(),
+        }
+    }
+}
+
+impl SpotifyError {
+    /// The HTTP status a web handler should respond with for this failure.
+    ///
+    /// Malformed or forged callbacks and rejected authorization codes are client errors (``400``),
+    /// denied consent is ``403``, rate limiting and full stores are ``503`` and other failures of the Spotify
+    /// Accounts service are ``502``. Configuration and local failures are ``500``.
+    pub fn http_status(&self) -> u16 {
+        match self.code() {
+            "invalid_callback" | "state_mismatch" | "invalid_state" | "code_replayed"
+            | "missing_code" | "invalid_grant" => 400,
+            "access_denied" => 403,
+            "rate_limited" | "capacity_exceeded" => 503,
+            "connect_error"
+            | "http_error"
+            | "response_too_large"
+            | "invalid_response"
+            | "accounts_unavailable"
+            | "token_request_failed" => 502,
+            _ => 500,
+        }
+    }
+
+    /// A message describing this failure which is safe to show to the user.
+    pub fn user_message(&self) -> &'static str {
+        match self.code() {
+            "invalid_callback" | "missing_code" => "The authorization callback is malformed.",
+            "state_mismatch" | "invalid_state" => {
+                "The authorization request could not be verified, please try again."
+            }
+            "access_denied" => "Access to your Spotify account was not granted.",
+            "invalid_grant" | "code_replayed" => {
+                "The authorization has expired or was already used, please log in again."
+            }
+            "rate_limited" => "Spotify is busy, please try again later.",
+            "capacity_exceeded" => "Too many logins are in progress, please try again later.",
+            _ if self.http_status() == 502 => {
+                "Spotify could not be reached, please try again later."
+            }
+            _ => "An internal error occurred.",
+        }
+    }
+
+    /// The problem details describing this failure.
+    pub fn to_problem(&self) -> Problem {
+        Problem::from(self)
+    }
+}
+
+/// The reason phrase of the HTTP statuses used for problems.
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        403 => "Forbidden",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SpotifyCallback;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_http_status() {
+        let malformed = SpotifyCallback::from_str("http://localhost:8888/callback").unwrap_err();
+        assert_eq!(malformed.http_status(), 400);
+
+        let garbled = SpotifyCallback::from_str("not a callback").unwrap_err();
+        assert_eq!(garbled.code(), "invalid_callback");
+        assert_eq!(garbled.http_status(), 400);
+
+        let forged = SpotifyCallback::new(Some("AQD0yXvFEOvw".into()), None, "sN".into())
+            .validate_state("other")
+            .unwrap_err();
+        assert_eq!(forged.http_status(), 400);
+
+        let unavailable = SpotifyError::ConnectError {
+            source: "connection refused".into(),
+        };
+        assert_eq!(unavailable.http_status(), 502);
+
+        let misconfigured = SpotifyError::ConfigFailure {
+            context: "Invalid proxy URL.",
+        };
+        assert_eq!(misconfigured.http_status(), 500);
+
+        let redirect_uri = SpotifyError::UrlError {
+            source: url::ParseError::RelativeUrlWithoutBase,
+        };
+        assert_eq!(redirect_uri.http_status(), 500);
+        assert_eq!(redirect_uri.user_message(), "An internal error occurred.");
+    }
+
+    #[test]
+    fn test_problem_json() {
+        let error = SpotifyError::ResponseFailure {
+            context: "Failed to convert callback into token",
+            status: 400,
+            error: Some("invalid_grant".into()),
+            body: r#"{"error":"invalid_grant","error_description":"Invalid authorization code"}"#
+                .into(),
+        };
+
+        assert_eq!(
+            error.to_problem().to_json(),
+            r#"{"type":"urn:spotify-oauth:error:invalid_grant","title":"Bad Request","status":400,"detail":"The authorization has expired or was already used, please log in again.","code":"invalid_grant"}"#
+        );
+    }
+}
@@ -0,0 +1,258 @@
+//! Protection against authorization codes being exchanged twice.
+
+use sha2::{Digest, Sha256};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::*;
+use crate::pending::PendingAuthorization;
+use crate::SpotifyToken;
+
+/// How long consumed codes are remembered by default, longer than Spotify authorization codes live.
+const DEFAULT_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// The result of a completed code exchange, kept to answer repeated callbacks.
+#[derive(Debug, Clone)]
+pub(crate) struct Issued {
+    pub request: Option<PendingAuthorization>,
+    pub token: SpotifyToken,
+}
+
+#[derive(Debug)]
+struct Consumed {
+    state: String,
+    consumed_at: i64,
+    issued: Option<Issued>,
+}
+
+/// Remembers consumed authorization codes and states.
+///
+/// A callback URL can be submitted twice, by a browser refresh or by someone replaying it. When
+/// a [`SpotifyOAuthClient`](crate::SpotifyOAuthClient) has a guard, a callback whose code or state
+/// was already used fails with ``code_replayed`` before the token endpoint is called.
+///
+/// A reissue window can be set to answer a repeated callback with the same code and state with the
+/// token already issued, so refreshing the callback page keeps working. The callback URL is then as
+/// good as the token for that long, so only enable it when the application ties the callback to the
+/// browser session which started the authorization, for example with a state cookie.
+///
+/// Codes are remembered as SHA-256 digests, so the guard never holds a usable code.
+///
+/// # Example
+///
+/// ```
+/// # use spotify_oauth::{ReplayGuard, ScriptedHttpClient, SpotifyOAuthClient};
+/// # use std::time::Duration;
+/// let client = SpotifyOAuthClient::new(ScriptedHttpClient::new(), "00000000000".into(), "secret".into(), "http://localhost:8000/callback".parse().unwrap())
+///     .with_replay_guard(ReplayGuard::new().with_reissue_window(Duration::from_secs(30)));
+/// ```
+pub struct ReplayGuard {
+    entries: Mutex<HashMap<String, Consumed>>,
+    ttl: Duration,
+    reissue_window: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl ReplayGuard {
+    /// Create a new guard remembering codes for 15 minutes, without reissuing tokens.
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            ttl: DEFAULT_TTL,
+            reissue_window: Duration::from_secs(0),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Remember consumed codes for the given duration.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Answer repeated callbacks with the same code and state with the already issued token for
+    /// the given duration after the exchange, disabled by default.
+    pub fn with_reissue_window(mut self, reissue_window: Duration) -> Self {
+        self.reissue_window = reissue_window;
+        self
+    }
+
+    /// Expire consumed codes and reissue windows with the given clock, defaults to [`SystemClock`].
+    pub fn with_clock<K: Clock + 'static>(mut self, clock: K) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Mark the code and state as consumed, or return the token already issued for them.
+    pub(crate) fn consume(&self, code: &str, state: &str) -> SpotifyResult<Option<Issued>> {
+        let now = self.clock.now().timestamp();
+        let ttl = self.ttl.as_secs() as i64;
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|_, x| now - x.consumed_at <= ttl);
+
+        let key = digest(code);
+        if let Some(consumed) = entries.get(&key) {
+            let reissue = consumed.state == state
+                && now - consumed.consumed_at <= self.reissue_window.as_secs() as i64;
+
+            return match consumed.issued {
+                Some(ref issued) if reissue => Ok(Some(issued.clone())),
+                _ => CodeReplayed {
+                    context: "The authorization code was already used.",
+                }
+                .fail(),
+            };
+        }
+
+        if entries.values().any(|x| x.state == state) {
+            return CodeReplayed {
+                context: "The state was already used.",
+            }
+            .fail();
+        }
+
+        entries.insert(
+            key,
+            Consumed {
+                state: state.to_string(),
+                consumed_at: now,
+                issued: None,
+            },
+        );
+
+        Ok(None)
+    }
+
+    /// Record the result of the exchange of a consumed code.
+    ///
+    /// Codes which never reached the token endpoint are released, so the callback can be retried.
+    pub(crate) fn complete(
+        &self,
+        code: &str,
+        request: Option<&PendingAuthorization>,
+        result: &SpotifyResult<SpotifyToken>,
+    ) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let key = digest(code);
+
+        match result {
+            Ok(token) if self.reissue_window.as_secs() > 0 => {
+                if let Some(consumed) = entries.get_mut(&key) {
+                    consumed.issued = Some(Issued {
+                        request: request.cloned(),
+                        token: token.clone(),
+                    });
+                }
+            }
+            Err(e) if e.code() == "connect_error" => {
+                entries.remove(&key);
+            }
+            _ => {}
+        }
+    }
+
+    /// Release a consumed code which was not exchanged.
+    pub(crate) fn release(&self, code: &str) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.remove(&digest(code));
+    }
+}
+
+/// Implementation of Default for ReplayGuard.
+///
+/// Equivalent to [`ReplayGuard::new`].
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for ReplayGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplayGuard")
+            .field("ttl", &self.ttl)
+            .field("reissue_window", &self.reissue_window)
+            .finish()
+    }
+}
+
+fn digest(code: &str) -> String {
+    base64::encode_config(&Sha256::digest(code.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::SpotifyScope;
+
+    fn token() -> SpotifyToken {
+        SpotifyToken {
+            access_token: "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw".into(),
+            token_type: "Bearer".to_string(),
+            scope: vec![SpotifyScope::Streaming],
+            expires_in: 3600,
+            expires_at: Some(1_577_836_800),
+            issued_at: Some(1_577_833_200),
+            refresh_token: "NgAagAHfVxDkSvCUm_SHo".into(),
+            client_id: None,
+        }
+    }
+
+    #[test]
+    fn test_reject_replay() {
+        let guard = ReplayGuard::new();
+        assert!(guard.consume("AQD0yXvFEOvw", "sN").unwrap().is_none());
+        guard.complete("AQD0yXvFEOvw", None, &Ok(token()));
+
+        let error = guard.consume("AQD0yXvFEOvw", "sN").unwrap_err();
+        assert_eq!(error.code(), "code_replayed");
+        assert_eq!(
+            error.to_string(),
+            "Authorization code replay: The authorization code was already used."
+        );
+
+        // A new code with a consumed state is rejected as well.
+        assert!(guard.consume("AQDc2Mf5Yv8", "sN").is_err());
+
+        // Codes which never reached Spotify can be used again.
+        guard.consume("AQBqzPz0", "other").unwrap();
+        guard.complete(
+            "AQBqzPz0",
+            None,
+            &Err(SpotifyError::ConnectError {
+                source: "connection refused".into(),
+            }),
+        );
+        assert!(guard.consume("AQBqzPz0", "other").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reissue() {
+        let clock = ManualClock::new(chrono::Utc::now());
+        let guard = ReplayGuard::new()
+            .with_reissue_window(Duration::from_secs(60))
+            .with_clock(clock.clone());
+        guard.consume("AQD0yXvFEOvw", "sN").unwrap();
+
+        // Still being exchanged.
+        assert!(guard.consume("AQD0yXvFEOvw", "sN").is_err());
+
+        guard.complete("AQD0yXvFEOvw", None, &Ok(token()));
+        let reissued = guard.consume("AQD0yXvFEOvw", "sN").unwrap().unwrap();
+        assert_eq!(reissued.token, token());
+
+        // Only for the state the code was issued with.
+        assert!(guard.consume("AQD0yXvFEOvw", "forged").is_err());
+
+        // And only within the window, after which the code is remembered until it expires.
+        clock.advance(Duration::from_secs(61));
+        assert!(guard.consume("AQD0yXvFEOvw", "sN").is_err());
+        clock.advance(DEFAULT_TTL);
+        assert!(guard.consume("AQD0yXvFEOvw", "sN").unwrap().is_none());
+    }
+}
@@ -0,0 +1,129 @@
+//! Retrying of failed token requests.
+
+use rand::Rng;
+
+use std::time::Duration;
+
+/// Policy for retrying token requests after transient failures.
+///
+/// Rate limited (``429``) responses and failures to connect are retried for every grant. Server
+/// errors and other transport failures are only retried for refresh and client credentials
+/// requests, as an authorization code may already have been consumed. Client errors such as
+/// ``invalid_grant`` are never retried.
+///
+/// The delay between attempts grows exponentially from ``base_delay`` up to ``max_delay``,
+/// unless the server gave a ``Retry-After`` header. A ``Retry-After`` longer than ``max_delay``
+/// ends the retries.
+///
+/// # Example
+///
+/// ```
+/// # use spotify_oauth::RetryPolicy;
+/// # use std::time::Duration;
+/// // Try up to five times, starting with a one second delay.
+/// let policy = RetryPolicy {
+///     max_attempts: 5,
+///     base_delay: Duration::from_secs(1),
+///     ..RetryPolicy::default()
+/// };
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// The delay before the first retry, doubled for every following retry.
+    pub base_delay: Duration,
+    /// The maximum delay between two attempts.
+    pub max_delay: Duration,
+    /// Whether to randomize up to half of each delay, so clients don't retry in lockstep.
+    pub jitter: bool,
+}
+
+/// Implementation of Default for RetryPolicy.
+///
+/// Up to 3 attempts are made, starting with a delay of 500 milliseconds and never waiting
+/// longer than 30 seconds.
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy which never retries.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// The delay before the next attempt after the given failed attempt, ``None`` if no attempts are left.
+    pub(crate) fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+
+        if let Some(x) = retry_after {
+            return if x <= self.max_delay { Some(x) } else { None };
+        }
+
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = self
+            .base_delay
+            .checked_mul(1 << exponent)
+            .map_or(self.max_delay, |x| x.min(self.max_delay));
+
+        if !self.jitter {
+            return Some(delay);
+        }
+
+        let half = delay / 2;
+        Some(half + half.mul_f64(rand::thread_rng().gen::<f64>()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+            jitter: false,
+        };
+
+        assert_eq!(policy.delay(1, None), Some(Duration::from_secs(1)));
+        assert_eq!(policy.delay(2, None), Some(Duration::from_secs(2)));
+        assert_eq!(policy.delay(4, None), Some(Duration::from_secs(5)));
+        assert_eq!(policy.delay(5, None), None);
+
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(3))),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(policy.delay(1, Some(Duration::from_secs(60))), None);
+    }
+
+    #[test]
+    fn test_delay_jitter() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(4),
+            ..RetryPolicy::default()
+        };
+
+        for _ in 0..20 {
+            let delay = policy.delay(1, None).unwrap();
+            assert!(delay >= Duration::from_secs(2) && delay <= Duration::from_secs(4));
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! Async runtime support.
+//!
+//! Token requests and the callback listener work on any executor. Background tasks such as the
+//! [`TokenManager`](crate::TokenManager) refresher need spawning, which is provided by either the
+//! ``runtime-tokio`` or the ``runtime-async-std`` feature. When both are enabled, tokio is used
+//! from within a tokio runtime and async-std everywhere else.
+//!
+//! Without either feature, timers used between retries and for request timeouts run on a single
+//! shared timer thread, and are cancelled when dropped.
+
+#[cfg(any(feature = "runtime-tokio", feature = "runtime-async-std"))]
+use std::future::Future;
+use std::time::Duration;
+
+/// Whether the caller is running inside a tokio runtime.
+#[cfg(all(feature = "runtime-tokio", feature = "runtime-async-std"))]
+fn in_tokio() -> bool {
+    tokio::runtime::Handle::try_current().is_ok()
+}
+
+/// Wait for the given duration on the current runtime.
+pub(crate) async fn sleep(duration: Duration) {
+    #[cfg(all(feature = "runtime-tokio", feature = "runtime-async-std"))]
+    {
+        if in_tokio() {
+            tokio::time::sleep(duration).await
+        } else {
+            async_std::task::sleep(duration).await
+        }
+    }
+
+    #[cfg(all(feature = "runtime-tokio", not(feature = "runtime-async-std")))]
+    tokio::time::sleep(duration).await;
+
+    #[cfg(all(not(feature = "runtime-tokio"), feature = "runtime-async-std"))]
+    async_std::task::sleep(duration).await;
+
+    #[cfg(not(any(feature = "runtime-tokio", feature = "runtime-async-std")))]
+    futures_timer::Delay::new(duration).await;
+}
+
+/// Run the future in the background on the current runtime.
+#[cfg(any(feature = "runtime-tokio", feature = "runtime-async-std"))]
+pub(crate) fn spawn<F: Future<Output = ()> + Send + 'static>(future: F) {
+    #[cfg(all(feature = "runtime-tokio", feature = "runtime-async-std"))]
+    {
+        if in_tokio() {
+            tokio::spawn(future);
+        } else {
+            async_std::task::spawn(future);
+        }
+    }
+
+    #[cfg(all(feature = "runtime-tokio", not(feature = "runtime-async-std")))]
+    tokio::spawn(future);
+
+    #[cfg(all(not(feature = "runtime-tokio"), feature = "runtime-async-std"))]
+    async_std::task::spawn(future);
+}
+
+#[cfg(all(
+    test,
+    any(
+        all(feature = "runtime-tokio", feature = "reqwest-client"),
+        all(feature = "runtime-async-std", feature = "surf-client")
+    )
+))]
+mod tests {
+    use async_trait::async_trait;
+
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    use crate::error::*;
+    use crate::*;
+
+    const TOKEN_RESPONSE: &str = r#"{"access_token":"NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw","token_type":"Bearer","scope":"streaming","expires_in":3600,"refresh_token":"NgAagAHfVxDkSvCUm_SHo"}"#;
+    const REFRESH_RESPONSE: &str =
+        r#"{"access_token":"NgA6ZcYIixn8bU","token_type":"Bearer","expires_in":3600}"#;
+
+    /// Sends every request to the mock endpoint instead of the Spotify Accounts service.
+    struct MockEndpoint<C> {
+        http: C,
+        url: String,
+    }
+
+    #[async_trait]
+    impl<C: HttpClient> HttpClient for MockEndpoint<C> {
+        async fn execute(&self, mut request: HttpRequest) -> SpotifyResult<HttpResponse> {
+            request.url = self.url.clone();
+            self.http.execute(request).await
+        }
+    }
+
+    /// Serve the given JSON bodies to successive requests, returning the request bodies received.
+    fn mock_endpoint(responses: Vec<&'static str>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/token", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                bodies.push(read_request_body(&mut stream));
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    response.len(),
+                    response
+                )
+                .unwrap();
+            }
+            bodies
+        });
+
+        (url, handle)
+    }
+
+    fn read_request_body(stream: &mut TcpStream) -> String {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 1024];
+        loop {
+            let n = stream.read(&mut chunk).unwrap();
+            buf.extend_from_slice(&chunk[..n]);
+
+            let request = String::from_utf8_lossy(&buf).into_owned();
+            if let Some(end) = request.find("\r\n\r\n") {
+                let length = request
+                    .lines()
+                    .filter_map(|x| x.split_once(':'))
+                    .find(|x| x.0.eq_ignore_ascii_case("content-length"))
+                    .map_or(0, |x| x.1.trim().parse().unwrap());
+
+                if buf.len() >= end + 4 + length {
+                    return request[end + 4..].to_owned();
+                }
+            }
+        }
+    }
+
+    fn free_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    /// Log in through the loopback listener and refresh the token against the mock endpoint.
+    async fn login_and_refresh<C: HttpClient>(http: C) {
+        let (url, server) = mock_endpoint(vec![TOKEN_RESPONSE, REFRESH_RESPONSE]);
+        let http = MockEndpoint { http, url };
+
+        let port = free_port();
+        let auth = SpotifyAuth::new(
+            "00000000000".into(),
+            "secret".into(),
+            "code".into(),
+            format!("http://127.0.0.1:{}/callback", port),
+            vec![SpotifyScope::Streaming],
+            false,
+        );
+
+        // Act as the browser being redirected back after authorization.
+        let state = auth.state.clone();
+        let browser = thread::spawn(move || loop {
+            if let Ok(mut stream) = TcpStream::connect(("127.0.0.1", port)) {
+                write!(
+                    stream,
+                    "GET /callback?code=AQD0yXvFEOvw&state={} HTTP/1.1\r\n\r\n",
+                    state
+                )
+                .unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        });
+
+        let token = login_with(&http, &auth, LoginStrategy::Loopback, None)
+            .await
+            .unwrap();
+        browser.join().unwrap();
+        assert_eq!(
+            token.access_token.expose_secret(),
+            "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw"
+        );
+
+        let refreshed = token
+            .refresh_with(&http, "00000000000", "secret")
+            .await
+            .unwrap();
+        assert_eq!(refreshed.access_token.expose_secret(), "NgA6ZcYIixn8bU");
+        assert_eq!(
+            refreshed.refresh_token.expose_secret(),
+            "NgAagAHfVxDkSvCUm_SHo"
+        );
+
+        let bodies = server.join().unwrap();
+        assert!(bodies[0].starts_with("grant_type=authorization_code&code=AQD0yXvFEOvw"));
+        assert_eq!(
+            bodies[1],
+            "grant_type=refresh_token&refresh_token=NgAagAHfVxDkSvCUm_SHo"
+        );
+    }
+
+    /// Let the background refresher replace an expired token using the mock endpoint.
+    async fn background_refresh<C: HttpClient + 'static>(http: C) {
+        let (url, server) = mock_endpoint(vec![REFRESH_RESPONSE]);
+        let token: SpotifyToken = serde_json::from_str(TOKEN_RESPONSE).unwrap();
+
+        let manager = Arc::new(TokenManager::new(
+            MockEndpoint { http, url },
+            "00000000000".into(),
+            "secret".into(),
+            token,
+        ));
+        let _refresher = manager.spawn_refresher();
+
+        for _ in 0..500 {
+            if manager.current().await.access_token.expose_secret() == "NgA6ZcYIixn8bU" {
+                server.join().unwrap();
+                return;
+            }
+            super::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("token was not refreshed in the background");
+    }
+
+    #[cfg(all(feature = "runtime-tokio", feature = "reqwest-client"))]
+    #[tokio::test]
+    async fn test_tokio_login_and_refresh() {
+        login_and_refresh(ReqwestClient::default()).await;
+    }
+
+    #[cfg(all(feature = "runtime-tokio", feature = "reqwest-client"))]
+    #[tokio::test]
+    async fn test_tokio_background_refresh() {
+        background_refresh(ReqwestClient::default()).await;
+    }
+
+    #[cfg(all(feature = "runtime-async-std", feature = "surf-client"))]
+    #[async_std::test]
+    async fn test_async_std_background_refresh() {
+        background_refresh(SurfClient::default()).await;
+    }
+
+    #[cfg(all(feature = "runtime-async-std", feature = "surf-client"))]
+    #[async_std::test]
+    async fn test_async_std_login_and_refresh() {
+        login_and_refresh(SurfClient::default()).await;
+    }
+}
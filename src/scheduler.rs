@@ -0,0 +1,543 @@
+//! Refreshing the tokens of many users ahead of their expiry.
+
+use futures::stream::{self, StreamExt};
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::*;
+use crate::grant::Grant;
+use crate::http::HttpClient;
+use crate::retry::RetryPolicy;
+use crate::store::UserTokenStore;
+use crate::{SecretString, SpotifyToken, SPOTIFY_TOKEN_URL};
+
+/// How long before expiry tokens are refreshed by default.
+const DEFAULT_REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+/// The largest random offset added to the refresh margin of each user by default.
+const DEFAULT_JITTER: Duration = Duration::from_secs(2 * 60);
+/// How many tokens are refreshed at the same time by default.
+const DEFAULT_CONCURRENCY: usize = 8;
+/// How long to wait after the first failed refresh of a user by default.
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(60);
+/// The longest wait between failed refreshes of a user by default.
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+/// The outcome of a refresh run of the [`RefreshScheduler`].
+#[derive(Debug, Default)]
+pub struct RefreshReport {
+    /// The users whose tokens were refreshed and saved.
+    pub refreshed: Vec<String>,
+    /// The users whose tokens were replaced elsewhere during the refresh, so the refreshed token
+    /// was discarded.
+    pub superseded: Vec<String>,
+    /// The users whose refresh tokens were rejected, who have to authorize the application again.
+    pub reauthorization_required: Vec<String>,
+    /// The users whose tokens were issued to another client, which this scheduler can't refresh.
+    pub client_mismatch: Vec<String>,
+    /// The users whose tokens could not be refreshed or saved, to be retried after a backoff.
+    pub failed: Vec<(String, SpotifyError)>,
+    /// The users skipped as they are waiting for their backoff after an earlier failure.
+    pub backed_off: Vec<String>,
+}
+
+/// The failed refreshes of a user.
+#[derive(Debug, Clone, Copy)]
+struct Backoff {
+    failures: u32,
+    retry_at: i64,
+}
+
+/// Refreshes the tokens of many users ahead of their expiry.
+///
+/// Every run loads the tokens expiring soon from a [`UserTokenStore`], refreshes them with a
+/// bounded number of concurrent requests and saves the results. Each user gets a random offset on
+/// top of the refresh margin, drawn once and kept for the lifetime of the scheduler, so tokens
+/// issued at the same time are not all refreshed at once. Failed refreshes are retried with an
+/// exponential backoff per user, and users whose refresh token is rejected with ``invalid_grant``
+/// are flagged in the store as having to authorize the application again. Tokens issued to another
+/// client are reported without being retried.
+///
+/// Call [`run_once`](Self::run_once) periodically, or with the ``runtime-tokio`` or
+/// ``runtime-async-std`` feature, let [`spawn`](Self::spawn) do it in the background.
+///
+/// # Example
+///
+/// ```no_run
+/// # use spotify_oauth::{MemoryUserTokenStore, RefreshScheduler, SurfClient};
+/// # #[async_std::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+/// let scheduler = RefreshScheduler::new(SurfClient::default(), "00000000000".into(), "secret".into(), MemoryUserTokenStore::new())
+///     .with_concurrency(16);
+///
+/// let report = scheduler.run_once().await?;
+/// for user_id in report.reauthorization_required {
+///     println!("{} has to log in again", user_id);
+/// }
+/// # Ok(()) }
+/// ```
+pub struct RefreshScheduler<C> {
+    http: C,
+    client_id: String,
+    client_secret: SecretString,
+    store: Arc<dyn UserTokenStore>,
+    refresh_margin: Duration,
+    jitter: Duration,
+    concurrency: usize,
+    backoff: Duration,
+    max_backoff: Duration,
+    retry: RetryPolicy,
+    clock: Arc<dyn Clock>,
+    rng: Mutex<Box<dyn RngCore + Send>>,
+    offsets: Mutex<HashMap<String, u64>>,
+    failures: Mutex<HashMap<String, Backoff>>,
+}
+
+impl<C: HttpClient> RefreshScheduler<C> {
+    /// Create a new scheduler refreshing the tokens of the given store with the given HTTP client
+    /// and application credentials.
+    pub fn new<S: UserTokenStore + 'static>(
+        http: C,
+        client_id: String,
+        client_secret: SecretString,
+        store: S,
+    ) -> Self {
+        Self {
+            http,
+            client_id,
+            client_secret,
+            store: Arc::new(store),
+            refresh_margin: DEFAULT_REFRESH_MARGIN,
+            jitter: DEFAULT_JITTER,
+            concurrency: DEFAULT_CONCURRENCY,
+            backoff: DEFAULT_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            retry: RetryPolicy::default(),
+            clock: Arc::new(SystemClock),
+            rng: Mutex::new(Box::new(OsRng)),
+            offsets: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Refresh tokens when they expire within the given margin, defaults to 5 minutes.
+    pub fn with_refresh_margin(mut self, refresh_margin: Duration) -> Self {
+        self.refresh_margin = refresh_margin;
+        self
+    }
+
+    /// Add a random offset of up to the given duration to the refresh margin of each user,
+    /// defaults to 2 minutes.
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Refresh at most the given number of tokens at the same time, defaults to 8.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Wait the given duration after the first failed refresh of a user, doubling for every
+    /// following failure up to the maximum. Defaults to 1 minute up to 1 hour.
+    pub fn with_backoff(mut self, backoff: Duration, max_backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Retry failed requests within a run according to the given policy, defaults to [`RetryPolicy::default`].
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Check expiry and schedule refreshes with the given clock, defaults to [`SystemClock`].
+    pub fn with_clock<K: Clock + 'static>(mut self, clock: K) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Draw the offsets of the users from the given random number generator, defaults to the
+    /// operating system random number generator.
+    pub fn with_rng<R: RngCore + Send + 'static>(mut self, rng: R) -> Self {
+        self.rng = Mutex::new(Box::new(rng));
+        self
+    }
+
+    /// The store the tokens are loaded from and saved into.
+    pub fn store(&self) -> &dyn UserTokenStore {
+        &*self.store
+    }
+
+    /// Refresh every token which is due, and save the results.
+    ///
+    /// Only failures of the store to list the expiring tokens are returned as errors, failures of
+    /// single users are collected in the report.
+    pub async fn run_once(&self) -> SpotifyResult<RefreshReport> {
+        let now = self.clock.now().timestamp();
+        let horizon = now + (self.refresh_margin + self.jitter).as_secs() as i64;
+        let mut report = RefreshReport::default();
+
+        let mut due = Vec::new();
+        for (user_id, token) in self.store.expiring_before(horizon)? {
+            if !self.is_due(&user_id, &token, now) {
+                continue;
+            }
+
+            if self.retry_at(&user_id).is_some_and(|x| x > now) {
+                report.backed_off.push(user_id);
+                continue;
+            }
+
+            due.push((user_id, token));
+        }
+
+        let results: Vec<(String, SpotifyResult<bool>)> = stream::iter(due)
+            .map(|(user_id, token)| async move {
+                let result = self.refresh(&user_id, &token).await;
+                (user_id, result)
+            })
+            .buffer_unordered(self.concurrency)
+            .collect()
+            .await;
+
+        for (user_id, result) in results {
+            match result {
+                Ok(true) => {
+                    self.clear_backoff(&user_id);
+                    report.refreshed.push(user_id);
+                }
+                Ok(false) => {
+                    self.clear_backoff(&user_id);
+                    report.superseded.push(user_id);
+                }
+                // Retrying can't help, the token has to be refreshed by the client it belongs to.
+                Err(e) if e.code() == "client_mismatch" => {
+                    self.clear_backoff(&user_id);
+                    report.client_mismatch.push(user_id);
+                }
+                Err(e) if e.requires_reauthorization() => {
+                    self.clear_backoff(&user_id);
+                    match self.store.mark_reauthorization_required(&user_id) {
+                        Ok(()) => report.reauthorization_required.push(user_id),
+                        Err(e) => report.failed.push((user_id, e)),
+                    }
+                }
+                Err(e) => {
+                    self.back_off(&user_id, now);
+                    report.failed.push((user_id, e));
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Refresh the token of the user and save it, unless it was refreshed elsewhere meanwhile.
+    ///
+    /// Returns whether the refreshed token was saved.
+    async fn refresh(&self, user_id: &str, token: &SpotifyToken) -> SpotifyResult<bool> {
+        token.verify_client(&self.client_id)?;
+
+        let refreshed = Grant::RefreshToken {
+            refresh_token: token.refresh_token.expose_secret(),
+        }
+        .execute(
+            &self.http,
+            SPOTIFY_TOKEN_URL,
+            &self.client_id,
+            self.client_secret.expose_secret(),
+            &self.retry,
+            &*self.clock,
+        )
+        .await?;
+
+        self.store.replace(user_id, token, &refreshed)
+    }
+
+    /// Whether the token of the user is within the refresh margin plus the offset of the user.
+    fn is_due(&self, user_id: &str, token: &SpotifyToken, now: i64) -> bool {
+        let expires_at = match token.lifetime(&*self.clock).expires_at() {
+            Some(x) => x,
+            None => return true,
+        };
+
+        let margin = self.refresh_margin.as_secs() + self.offset(user_id);
+        expires_at - (margin as i64) <= now
+    }
+
+    /// The offset of the user, up to the configured jitter.
+    fn offset(&self, user_id: &str) -> u64 {
+        let max = self.jitter.as_secs();
+        if max == 0 {
+            return 0;
+        }
+
+        let mut offsets = self.offsets.lock().unwrap_or_else(|e| e.into_inner());
+        let offset = *offsets.entry(user_id.to_string()).or_insert_with(|| {
+            self.rng
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .next_u64()
+        });
+        offset % (max + 1)
+    }
+
+    fn retry_at(&self, user_id: &str) -> Option<i64> {
+        let failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        failures.get(user_id).map(|x| x.retry_at)
+    }
+
+    fn back_off(&self, user_id: &str, now: i64) {
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        let backoff = failures.entry(user_id.to_string()).or_insert(Backoff {
+            failures: 0,
+            retry_at: now,
+        });
+
+        let exponent = backoff.failures.min(31);
+        let delay = self
+            .backoff
+            .checked_mul(1 << exponent)
+            .map_or(self.max_backoff, |x| x.min(self.max_backoff));
+
+        backoff.failures += 1;
+        backoff.retry_at = now + delay.as_secs() as i64;
+    }
+
+    fn clear_backoff(&self, user_id: &str) {
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        failures.remove(user_id);
+    }
+}
+
+#[cfg(any(feature = "runtime-tokio", feature = "runtime-async-std"))]
+impl<C: HttpClient + 'static> RefreshScheduler<C> {
+    /// Run the scheduler in the background on the enabled runtime, every given interval.
+    ///
+    /// The scheduler runs until the returned handle is dropped or stopped. Reports are discarded,
+    /// the store holds the results.
+    pub fn spawn(self: &Arc<Self>, interval: Duration) -> crate::RefresherHandle {
+        let scheduler = Arc::clone(self);
+        crate::manager::spawn_task(async move {
+            loop {
+                let _ = scheduler.run_once().await;
+                crate::rt::sleep(interval).await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HttpResponse, ManualClock, MemoryUserTokenStore, ScriptedHttpClient};
+    use chrono::{TimeZone, Utc};
+    use futures::executor::block_on;
+    use rand::rngs::mock::StepRng;
+
+    const START: i64 = 1_577_836_800;
+
+    fn token(expires_at: i64, refresh_token: &str) -> SpotifyToken {
+        SpotifyToken {
+            access_token: "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw".into(),
+            token_type: "Bearer".to_string(),
+            scope: vec![],
+            expires_in: 3600,
+            expires_at: Some(expires_at),
+            issued_at: Some(expires_at - 3600),
+            refresh_token: refresh_token.into(),
+            client_id: None,
+        }
+    }
+
+    fn scheduler<'a>(
+        http: &'a ScriptedHttpClient,
+        clock: &ManualClock,
+    ) -> RefreshScheduler<&'a ScriptedHttpClient> {
+        let store = MemoryUserTokenStore::new();
+        store.save("expiring", &token(START + 60, "a")).unwrap();
+        store.save("revoked", &token(START + 60, "b")).unwrap();
+        store.save("valid", &token(START + 3600, "c")).unwrap();
+
+        RefreshScheduler::new(http, "id".into(), "secret".into(), store)
+            .with_jitter(Duration::from_secs(0))
+            .with_retry_policy(RetryPolicy::none())
+            .with_concurrency(1)
+            .with_clock(clock.clone())
+    }
+
+    #[test]
+    fn test_refresh_due_tokens() {
+        let http = ScriptedHttpClient::new();
+        let clock = ManualClock::new(Utc.timestamp_opt(START, 0).unwrap());
+        let scheduler = scheduler(&http, &clock);
+
+        // Tokens are listed in no particular order, so either user may get either response.
+        http.push_response(HttpResponse::new(
+            200,
+            r#"{"access_token":"NgA6ZcYIixn8bU","token_type":"Bearer","expires_in":3600}"#,
+        ));
+        http.push_response(HttpResponse::new(400, r#"{"error":"invalid_grant"}"#));
+        let report = block_on(scheduler.run_once()).unwrap();
+
+        assert_eq!(report.refreshed.len(), 1);
+        assert_eq!(report.reauthorization_required.len(), 1);
+        let refreshed = &report.refreshed[0];
+        let revoked = &report.reauthorization_required[0];
+
+        let store = scheduler.store();
+        assert_eq!(
+            store.load(refreshed).unwrap().unwrap().expires_at,
+            Some(START + 3600)
+        );
+        assert!(store.requires_reauthorization(revoked).unwrap());
+        assert!(!store.requires_reauthorization("valid").unwrap());
+        assert_eq!(http.requests().len(), 2);
+
+        // Nothing is due on the next run.
+        let report = block_on(scheduler.run_once()).unwrap();
+        assert!(report.refreshed.is_empty() && report.failed.is_empty());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let http = ScriptedHttpClient::new();
+        let clock = ManualClock::new(Utc.timestamp_opt(START, 0).unwrap());
+        let scheduler = scheduler(&http, &clock)
+            .with_backoff(Duration::from_secs(60), Duration::from_secs(600));
+
+        http.push_response(HttpResponse::new(503, ""));
+        http.push_response(HttpResponse::new(503, ""));
+        let report = block_on(scheduler.run_once()).unwrap();
+        assert_eq!(report.failed.len(), 2);
+
+        // Both users wait for their backoff.
+        clock.advance(Duration::from_secs(30));
+        let report = block_on(scheduler.run_once()).unwrap();
+        assert_eq!(report.backed_off.len(), 2);
+        assert_eq!(http.requests().len(), 2);
+
+        // And are retried after it.
+        clock.advance(Duration::from_secs(30));
+        http.push_response(HttpResponse::new(503, ""));
+        http.push_response(HttpResponse::new(503, ""));
+        let report = block_on(scheduler.run_once()).unwrap();
+        assert_eq!(report.failed.len(), 2);
+        assert_eq!(scheduler.retry_at("expiring"), Some(START + 60 + 120));
+    }
+
+    #[test]
+    fn test_jitter() {
+        let http = ScriptedHttpClient::new();
+        let clock = ManualClock::new(Utc.timestamp_opt(START, 0).unwrap());
+        let scheduler = scheduler(&http, &clock).with_rng(StepRng::new(100, 21));
+        assert_eq!(scheduler.offset("4kCdJ4Pkv3dD"), 0);
+
+        // Offsets are drawn once per user and kept.
+        let scheduler = scheduler.with_jitter(Duration::from_secs(120));
+        assert_eq!(scheduler.offset("4kCdJ4Pkv3dD"), 100);
+        assert_eq!(scheduler.offset("8fA2xZq0"), 0);
+        assert_eq!(scheduler.offset("4kCdJ4Pkv3dD"), 100);
+    }
+
+    #[test]
+    fn test_client_mismatch() {
+        let http = ScriptedHttpClient::new();
+        let clock = ManualClock::new(Utc.timestamp_opt(START, 0).unwrap());
+        let scheduler = scheduler(&http, &clock);
+
+        let mut token = token(START + 60, "d");
+        token.client_id = Some("staging".into());
+        scheduler.store().save("staging", &token).unwrap();
+        http.push_response(HttpResponse::new(503, ""));
+        http.push_response(HttpResponse::new(503, ""));
+
+        // Reported on every run without a request or a backoff.
+        for _ in 0..2 {
+            let report = block_on(scheduler.run_once()).unwrap();
+            assert_eq!(report.client_mismatch, vec!["staging".to_string()]);
+            assert_eq!(scheduler.retry_at("staging"), None);
+            assert!(!scheduler
+                .store()
+                .requires_reauthorization("staging")
+                .unwrap());
+        }
+        assert_eq!(http.requests().len(), 2);
+    }
+
+    /// A store whose tokens are replaced by another process while they are being refreshed.
+    struct RacingStore(MemoryUserTokenStore);
+
+    impl UserTokenStore for RacingStore {
+        fn load(&self, user_id: &str) -> SpotifyResult<Option<SpotifyToken>> {
+            self.0.load(user_id)
+        }
+
+        fn save(&self, user_id: &str, token: &SpotifyToken) -> SpotifyResult<()> {
+            self.0.save(user_id, token)
+        }
+
+        fn replace(
+            &self,
+            user_id: &str,
+            current: &SpotifyToken,
+            token: &SpotifyToken,
+        ) -> SpotifyResult<bool> {
+            let mut rotated = current.clone();
+            rotated.access_token = "NgBB2dVcpZq8".into();
+            self.0.save(user_id, &rotated)?;
+            self.0.replace(user_id, current, token)
+        }
+
+        fn expiring_before(&self, timestamp: i64) -> SpotifyResult<Vec<(String, SpotifyToken)>> {
+            self.0.expiring_before(timestamp)
+        }
+
+        fn mark_reauthorization_required(&self, user_id: &str) -> SpotifyResult<()> {
+            self.0.mark_reauthorization_required(user_id)
+        }
+
+        fn requires_reauthorization(&self, user_id: &str) -> SpotifyResult<bool> {
+            self.0.requires_reauthorization(user_id)
+        }
+    }
+
+    #[test]
+    fn test_superseded_refresh() {
+        let http = ScriptedHttpClient::new();
+        let clock = ManualClock::new(Utc.timestamp_opt(START, 0).unwrap());
+        let store = RacingStore(MemoryUserTokenStore::new());
+        store.save("expiring", &token(START + 60, "a")).unwrap();
+        let scheduler = RefreshScheduler::new(&http, "id".into(), "secret".into(), store)
+            .with_jitter(Duration::from_secs(0))
+            .with_retry_policy(RetryPolicy::none())
+            .with_clock(clock);
+
+        http.push_response(HttpResponse::new(
+            200,
+            r#"{"access_token":"NgA6ZcYIixn8bU","token_type":"Bearer","expires_in":3600}"#,
+        ));
+        let report = block_on(scheduler.run_once()).unwrap();
+
+        // The token saved by the other process is kept.
+        assert!(report.refreshed.is_empty());
+        assert_eq!(report.superseded, vec!["expiring".to_string()]);
+        assert_eq!(
+            scheduler
+                .store()
+                .load("expiring")
+                .unwrap()
+                .unwrap()
+                .access_token
+                .expose_secret(),
+            "NgBB2dVcpZq8"
+        );
+    }
+}
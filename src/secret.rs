@@ -0,0 +1,100 @@
+//! Secret values such as client secrets and tokens.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use zeroize::Zeroize;
+
+use std::fmt;
+
+/// A string which must not end up in logs.
+///
+/// The value is redacted by ``Debug`` and ``Display`` and zeroized when dropped, so it can only be
+/// read with [`expose_secret`](Self::expose_secret). Serializing the value writes it in plain text,
+/// so tokens can still be stored.
+///
+/// # Example
+///
+/// ```
+/// # use spotify_oauth::SecretString;
+/// let secret = SecretString::from("NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw");
+///
+/// assert_eq!(format!("{:?}", secret), "[REDACTED]");
+/// assert_eq!(secret.expose_secret(), "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw");
+/// ```
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct SecretString(String);
+
+impl SecretString {
+    /// Wrap the given value.
+    pub fn new(secret: String) -> Self {
+        Self(secret)
+    }
+
+    /// The secret value.
+    pub fn expose_secret(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether the secret is empty.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        Self::new(secret)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(secret: &str) -> Self {
+        Self::new(secret.to_owned())
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+impl fmt::Display for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Serialize for SecretString {
+    fn serialize<S: Serializer>(&self, se: S) -> Result<S::Ok, S::Error> {
+        se.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        String::deserialize(de).map(Self::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_redacted() {
+        let secret = SecretString::from("secret");
+
+        assert_eq!(format!("{:?} {}", secret, secret), "[REDACTED] [REDACTED]");
+        assert_eq!(serde_json::to_string(&secret).unwrap(), r#""secret""#);
+        assert_eq!(
+            serde_json::from_str::<SecretString>(r#""secret""#).unwrap(),
+            secret
+        );
+    }
+}
@@ -0,0 +1,308 @@
+//! Stateless ``state`` parameters signed with HMAC-SHA256.
+//!
+//! Servers running on several hosts can't keep the state of every authorization request in
+//! memory. A [`StateSigner`] instead encodes everything needed to handle the callback in the
+//! state itself, signed with a key shared by all hosts, so any host can verify it.
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use snafu::ResultExt;
+use zeroize::Zeroizing;
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::*;
+use crate::state::StateGenerator;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The smallest signing key accepted, in bytes.
+const MIN_KEY_SIZE: usize = 32;
+/// How long a signed state is valid by default.
+const DEFAULT_TTL: Duration = Duration::from_secs(10 * 60);
+/// How far in the future a state may have been issued, to allow for clock differences between hosts.
+const MAX_CLOCK_SKEW: i64 = 60;
+
+/// The payload of a verified signed state.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignedState {
+    /// The random nonce making every state unique.
+    #[serde(rename = "n")]
+    pub nonce: String,
+    /// The unix timestamp at which the state was issued.
+    #[serde(rename = "iat")]
+    pub issued_at: i64,
+    /// A reference to the PKCE code verifier kept by the application.
+    #[serde(rename = "pkce", default, skip_serializing_if = "Option::is_none")]
+    pub verifier_ref: Option<String>,
+    /// The local path to return the user to after the authorization.
+    #[serde(rename = "ret", default, skip_serializing_if = "Option::is_none")]
+    pub return_path: Option<String>,
+}
+
+/// Signs and verifies stateless ``state`` parameters.
+///
+/// A signed state is the URL-safe base64 encoded payload followed by a dot and its HMAC-SHA256
+/// signature. Verification rejects states which were tampered with, have expired or carry a
+/// return path which is not local to the application.
+///
+/// # Example
+///
+/// ```
+/// # use spotify_oauth::{SpotifyAuth, SpotifyCallback, SpotifyScope, StateSigner};
+/// # use std::str::FromStr;
+/// let signer = StateSigner::new(b"a shared key of at least 32 bytes!".to_vec()).unwrap();
+///
+/// // Any host can create the authorization request...
+/// let mut auth = SpotifyAuth::new("00000000000".into(), "secret".into(), "code".into(), "http://localhost:8000/callback".into(), vec![SpotifyScope::Streaming], false);
+/// auth.state = signer.sign(Some("/playlists"), None).unwrap();
+///
+/// // ...and any host can verify the callback.
+/// # let callback = SpotifyCallback::new(Some("NApCCgBkWtQ".to_string()), None, auth.state.clone());
+/// let state = signer.verify(callback.state()).unwrap();
+/// assert_eq!(state.return_path.as_deref(), Some("/playlists"));
+/// ```
+#[derive(Clone)]
+pub struct StateSigner {
+    key: Zeroizing<Vec<u8>>,
+    ttl: Duration,
+    nonce: StateGenerator,
+    clock: Arc<dyn Clock>,
+}
+
+impl StateSigner {
+    /// Create a new signer with the given key, which must be at least 32 bytes long.
+    pub fn new(key: Vec<u8>) -> SpotifyResult<Self> {
+        let key = Zeroizing::new(key);
+
+        if key.len() < MIN_KEY_SIZE {
+            return ConfigFailure {
+                context: "The state signing key must be at least 32 bytes long.",
+            }
+            .fail();
+        }
+
+        Ok(Self {
+            key,
+            ttl: DEFAULT_TTL,
+            nonce: StateGenerator::new().with_entropy(16),
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// Reject states older than the given duration, defaults to 10 minutes.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Generate nonces with the given generator.
+    pub fn with_state_generator(mut self, nonce: StateGenerator) -> Self {
+        self.nonce = nonce;
+        self
+    }
+
+    /// Date and check the age of states with the given clock, defaults to [`SystemClock`].
+    pub fn with_clock<K: Clock + 'static>(mut self, clock: K) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Create a new signed state with the given return path and PKCE verifier reference.
+    ///
+    /// The return path must be a local path such as ``/playlists?page=2``.
+    pub fn sign(
+        &self,
+        return_path: Option<&str>,
+        verifier_ref: Option<&str>,
+    ) -> SpotifyResult<String> {
+        if !return_path.map_or(true, is_local_path) {
+            return InvalidState {
+                context: "Return path is not a local path.",
+            }
+            .fail();
+        }
+
+        let payload = SignedState {
+            nonce: self.nonce.generate(),
+            issued_at: self.clock.now().timestamp(),
+            verifier_ref: verifier_ref.map(String::from),
+            return_path: return_path.map(String::from),
+        };
+
+        let payload = serde_json::to_vec(&payload).context(SerdeError)?;
+        let payload = base64::encode_config(&payload, base64::URL_SAFE_NO_PAD);
+        let signature = self.mac(&payload).finalize().into_bytes();
+
+        Ok(format!(
+            "{}.{}",
+            payload,
+            base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)
+        ))
+    }
+
+    /// Verify the signed state returned in a callback and return its payload.
+    pub fn verify(&self, state: &str) -> SpotifyResult<SignedState> {
+        let malformed = SpotifyError::InvalidState {
+            context: "Malformed state.",
+        };
+
+        let (payload, signature) = match state.split_once('.') {
+            Some(x) => x,
+            None => return Err(malformed),
+        };
+        let signature = match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+            Ok(x) => x,
+            Err(_) => return Err(malformed),
+        };
+
+        // Compare in constant time, so the signature can't be guessed byte by byte.
+        if self.mac(payload).verify_slice(&signature).is_err() {
+            return InvalidState {
+                context: "State signature does not match.",
+            }
+            .fail();
+        }
+
+        let state: SignedState = match base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|x| serde_json::from_slice(&x).ok())
+        {
+            Some(x) => x,
+            None => return Err(malformed),
+        };
+
+        let age = self.clock.now().timestamp() - state.issued_at;
+        if age > self.ttl.as_secs() as i64 || age < -MAX_CLOCK_SKEW {
+            return InvalidState {
+                context: "State has expired.",
+            }
+            .fail();
+        }
+
+        if !state.return_path.as_deref().map_or(true, is_local_path) {
+            return InvalidState {
+                context: "Return path is not a local path.",
+            }
+            .fail();
+        }
+
+        Ok(state)
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+impl fmt::Debug for StateSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StateSigner")
+            .field("key", &"[REDACTED]")
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+/// Whether the path stays on the application, so redirecting to it can't send the user elsewhere.
+///
+/// Protocol relative paths such as ``//example.com`` and backslashes, which browsers treat as
+/// slashes, are rejected.
+pub(crate) fn is_local_path(path: &str) -> bool {
+    path.starts_with('/')
+        && !path.starts_with("//")
+        && !path.contains('\\')
+        && !path.chars().any(char::is_control)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use chrono::Utc;
+
+    fn signer() -> StateSigner {
+        StateSigner::new(vec![7; 32]).unwrap()
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = signer();
+        let state = signer.sign(Some("/playlists?page=2"), Some("v1")).unwrap();
+        let verified = signer.verify(&state).unwrap();
+
+        assert_eq!(verified.return_path.as_deref(), Some("/playlists?page=2"));
+        assert_eq!(verified.verifier_ref.as_deref(), Some("v1"));
+        assert_ne!(
+            signer.sign(None, None).unwrap(),
+            signer.sign(None, None).unwrap()
+        );
+
+        assert!(StateSigner::new(vec![7; 16]).is_err());
+    }
+
+    #[test]
+    fn test_reject_tampering() {
+        let signer = signer();
+        let state = signer.sign(Some("/home"), None).unwrap();
+        let (_, signature) = state.split_once('.').unwrap();
+
+        // A payload with a different return path but the original signature.
+        let forged = SignedState {
+            nonce: "nonce".into(),
+            issued_at: Utc::now().timestamp(),
+            verifier_ref: None,
+            return_path: Some("/admin".into()),
+        };
+        let forged = format!(
+            "{}.{}",
+            base64::encode_config(
+                &serde_json::to_vec(&forged).unwrap(),
+                base64::URL_SAFE_NO_PAD
+            ),
+            signature
+        );
+
+        assert_eq!(
+            signer.verify(&forged).unwrap_err().to_string(),
+            "Invalid state: State signature does not match."
+        );
+        assert!(signer.verify("sN").is_err());
+        assert!(StateSigner::new(vec![8; 32])
+            .unwrap()
+            .verify(&state)
+            .is_err());
+    }
+
+    #[test]
+    fn test_reject_expired_and_open_redirects() {
+        let issued_at = Utc::now();
+        let clock = ManualClock::new(issued_at);
+        let signer = signer().with_clock(clock.clone());
+        let state = signer.sign(None, None).unwrap();
+
+        clock.advance(DEFAULT_TTL);
+        assert!(signer.verify(&state).is_ok());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(signer.verify(&state).unwrap_err().code(), "invalid_state");
+
+        // States issued too far in the future are rejected as well.
+        clock.set(issued_at - chrono::Duration::seconds(MAX_CLOCK_SKEW + 1));
+        assert_eq!(signer.verify(&state).unwrap_err().code(), "invalid_state");
+
+        for path in &[
+            "https://example.com",
+            "//example.com",
+            "/\\example.com",
+            "home",
+        ] {
+            assert!(signer.sign(Some(path), None).is_err());
+        }
+    }
+}
@@ -0,0 +1,437 @@
+//! Persistence for the tokens of many users in SQLite.
+
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Row, TransactionBehavior};
+use snafu::ResultExt;
+
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::*;
+use crate::{SpotifyScope, SpotifyToken, UserTokenStore};
+
+/// The schema migrations, applied in order. The ``user_version`` of the database is the number of
+/// migrations which have been applied.
+const MIGRATIONS: &[&str] = &[r#"
+    CREATE TABLE spotify_tokens (
+        user_id TEXT NOT NULL,
+        client_id TEXT NOT NULL,
+        access_token TEXT NOT NULL,
+        token_type TEXT NOT NULL,
+        scope TEXT NOT NULL,
+        expires_in INTEGER NOT NULL,
+        expires_at INTEGER,
+        issued_at INTEGER,
+        refresh_token TEXT NOT NULL,
+        refreshed_at INTEGER NOT NULL,
+        refresh_count INTEGER NOT NULL DEFAULT 0,
+        reauthorization_required INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (user_id, client_id)
+    );
+
+    CREATE INDEX spotify_tokens_expiry
+        ON spotify_tokens (client_id, reauthorization_required, expires_at);
+"#];
+
+/// The columns a token is read from, in the order expected by [`token_from_columns`].
+const TOKEN_COLUMNS: &str =
+    "access_token, token_type, scope, expires_in, expires_at, issued_at, refresh_token";
+
+/// A User Token Store keeping the tokens in a SQLite database.
+///
+/// Tokens are keyed by the Spotify user ID and the client ID of the application, so several
+/// applications can share one database. Saving a token issued to a different client is rejected.
+/// Besides the token, the time of the last refresh and the number of refreshes are recorded. The
+/// schema is created and migrated when the store is opened, and databases migrated by a newer
+/// version of the crate are rejected.
+///
+/// Tokens are stored with the timestamp they expire at, estimated from ``issued_at`` and
+/// ``expires_in`` if the token has no ``expires_at``, so tokens expiring before a given time are
+/// found with an indexed query. [`replace`](UserTokenStore::replace) compares and swaps the
+/// token in a single statement, so concurrent refreshes do not lose a rotated refresh token.
+///
+/// Requires the ``sqlite`` feature.
+///
+/// # Example
+///
+/// ```
+/// # use spotify_oauth::{SqliteTokenStore, UserTokenStore};
+/// let store = SqliteTokenStore::open_in_memory("00000000000").unwrap();
+/// assert_eq!(store.load("4kCdJ4Pkv3dD").unwrap(), None);
+/// ```
+#[derive(Debug)]
+pub struct SqliteTokenStore {
+    connection: Mutex<Connection>,
+    client_id: String,
+    clock: Arc<dyn Clock>,
+}
+
+impl SqliteTokenStore {
+    /// Open the database at the given path for the tokens of the given client, creating it if
+    /// it does not exist.
+    pub fn open<P: AsRef<Path>>(path: P, client_id: &str) -> SpotifyResult<Self> {
+        Self::from_connection(Connection::open(path).context(DatabaseError)?, client_id)
+    }
+
+    /// Open a new in-memory database for the tokens of the given client.
+    pub fn open_in_memory(client_id: &str) -> SpotifyResult<Self> {
+        Self::from_connection(
+            Connection::open_in_memory().context(DatabaseError)?,
+            client_id,
+        )
+    }
+
+    /// Use an open database connection for the tokens of the given client, migrating its schema.
+    pub fn from_connection(mut connection: Connection, client_id: &str) -> SpotifyResult<Self> {
+        migrate(&mut connection)?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+            client_id: client_id.to_string(),
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// Date saved tokens with the given clock, defaults to [`SystemClock`].
+    pub fn with_clock<K: Clock + 'static>(mut self, clock: K) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The client ID the tokens of this store belong to.
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    /// When the token of the user was last saved, and how many times it has been refreshed.
+    pub fn last_refresh(&self, user_id: &str) -> SpotifyResult<Option<(DateTime<Utc>, u32)>> {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+
+        connection
+            .query_row(
+                "SELECT refreshed_at, refresh_count FROM spotify_tokens
+                    WHERE user_id = ?1 AND client_id = ?2",
+                params![user_id, self.client_id],
+                |row| {
+                    let at = row.get::<_, i64>(0)?;
+                    let at = Utc
+                        .timestamp_opt(at, 0)
+                        .single()
+                        .ok_or_else(|| rusqlite::Error::IntegralValueOutOfRange(0, at))?;
+                    Ok((at, row.get(1)?))
+                },
+            )
+            .optional()
+            .context(DatabaseError)
+    }
+}
+
+impl UserTokenStore for SqliteTokenStore {
+    fn load(&self, user_id: &str) -> SpotifyResult<Option<SpotifyToken>> {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+
+        connection
+            .query_row(
+                &format!(
+                    "SELECT {} FROM spotify_tokens WHERE user_id = ?1 AND client_id = ?2",
+                    TOKEN_COLUMNS
+                ),
+                params![user_id, self.client_id],
+                |row| token_from_columns(row, 0, &self.client_id),
+            )
+            .optional()
+            .context(DatabaseError)
+    }
+
+    fn save(&self, user_id: &str, token: &SpotifyToken) -> SpotifyResult<()> {
+        token.verify_client(&self.client_id)?;
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+
+        connection
+            .execute(
+                "INSERT INTO spotify_tokens (user_id, client_id, access_token, token_type, scope,
+                    expires_in, expires_at, issued_at, refresh_token, refreshed_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+                ON CONFLICT (user_id, client_id) DO UPDATE SET
+                    access_token = excluded.access_token,
+                    token_type = excluded.token_type,
+                    scope = excluded.scope,
+                    expires_in = excluded.expires_in,
+                    expires_at = excluded.expires_at,
+                    issued_at = excluded.issued_at,
+                    refresh_token = excluded.refresh_token,
+                    refreshed_at = excluded.refreshed_at,
+                    reauthorization_required = 0",
+                params![
+                    user_id,
+                    self.client_id,
+                    token.access_token.expose_secret(),
+                    token.token_type,
+                    scope_into_string(&token.scope),
+                    token.expires_in,
+                    token.estimated_expires_at(),
+                    token.issued_at,
+                    token.refresh_token.expose_secret(),
+                    self.clock.now().timestamp(),
+                ],
+            )
+            .context(DatabaseError)
+            .map(|_| ())
+    }
+
+    fn replace(
+        &self,
+        user_id: &str,
+        current: &SpotifyToken,
+        token: &SpotifyToken,
+    ) -> SpotifyResult<bool> {
+        token.verify_client(&self.client_id)?;
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+
+        connection
+            .execute(
+                "UPDATE spotify_tokens SET
+                    access_token = ?4,
+                    token_type = ?5,
+                    scope = ?6,
+                    expires_in = ?7,
+                    expires_at = ?8,
+                    issued_at = ?9,
+                    refresh_token = ?10,
+                    refreshed_at = ?11,
+                    refresh_count = refresh_count + 1,
+                    reauthorization_required = 0
+                WHERE user_id = ?1 AND client_id = ?2 AND access_token = ?3",
+                params![
+                    user_id,
+                    self.client_id,
+                    current.access_token.expose_secret(),
+                    token.access_token.expose_secret(),
+                    token.token_type,
+                    scope_into_string(&token.scope),
+                    token.expires_in,
+                    token.estimated_expires_at(),
+                    token.issued_at,
+                    token.refresh_token.expose_secret(),
+                    self.clock.now().timestamp(),
+                ],
+            )
+            .context(DatabaseError)
+            .map(|x| x == 1)
+    }
+
+    fn expiring_before(&self, timestamp: i64) -> SpotifyResult<Vec<(String, SpotifyToken)>> {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let mut statement = connection
+            .prepare(&format!(
+                "SELECT user_id, {} FROM spotify_tokens
+                    WHERE client_id = ?1 AND reauthorization_required = 0
+                    AND (expires_at IS NULL OR expires_at < ?2)",
+                TOKEN_COLUMNS
+            ))
+            .context(DatabaseError)?;
+
+        let rows = statement
+            .query_map(params![self.client_id, timestamp], |row| {
+                Ok((row.get(0)?, token_from_columns(row, 1, &self.client_id)?))
+            })
+            .context(DatabaseError)?;
+
+        rows.collect::<Result<_, _>>().context(DatabaseError)
+    }
+
+    fn mark_reauthorization_required(&self, user_id: &str) -> SpotifyResult<()> {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+
+        connection
+            .execute(
+                "UPDATE spotify_tokens SET reauthorization_required = 1
+                    WHERE user_id = ?1 AND client_id = ?2",
+                params![user_id, self.client_id],
+            )
+            .context(DatabaseError)
+            .map(|_| ())
+    }
+
+    fn requires_reauthorization(&self, user_id: &str) -> SpotifyResult<bool> {
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+
+        connection
+            .query_row(
+                "SELECT reauthorization_required FROM spotify_tokens
+                    WHERE user_id = ?1 AND client_id = ?2",
+                params![user_id, self.client_id],
+                |row| row.get(0),
+            )
+            .optional()
+            .context(DatabaseError)
+            .map(|x| x.unwrap_or(false))
+    }
+}
+
+/// Apply the migrations the database has not seen yet, in a single transaction.
+///
+/// The transaction takes the write lock before reading the version, so processes opening the same
+/// database at once do not apply the same migrations twice. Databases with more migrations than are known are left untouched and rejected.
+fn migrate(connection: &mut Connection) -> SpotifyResult<()> {
+    let transaction = connection
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .context(DatabaseError)?;
+    let version: usize = transaction
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .context(DatabaseError)?;
+
+    if version > MIGRATIONS.len() {
+        return UnsupportedFormat {
+            version: version as u64,
+        }
+        .fail();
+    }
+
+    for migration in MIGRATIONS.iter().skip(version) {
+        transaction
+            .execute_batch(migration)
+            .context(DatabaseError)?;
+    }
+    transaction
+        .pragma_update(None, "user_version", MIGRATIONS.len())
+        .context(DatabaseError)?;
+
+    transaction.commit().context(DatabaseError)
+}
+
+/// Concatenate the scopes into a space separated string, as in token responses.
+fn scope_into_string(scope: &[SpotifyScope]) -> String {
+    scope
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Read a token of the given client from the [`TOKEN_COLUMNS`] of a row, starting at the given
+/// column.
+fn token_from_columns(
+    row: &Row<'_>,
+    start: usize,
+    client_id: &str,
+) -> rusqlite::Result<SpotifyToken> {
+    // Scopes unknown to this version of the crate are skipped, as in token responses.
+    let scope = row
+        .get::<_, String>(start + 2)?
+        .split_whitespace()
+        .filter_map(|x| SpotifyScope::from_str(x).ok())
+        .collect();
+
+    Ok(SpotifyToken {
+        access_token: row.get::<_, String>(start)?.into(),
+        token_type: row.get(start + 1)?,
+        scope,
+        expires_in: row.get(start + 3)?,
+        expires_at: row.get(start + 4)?,
+        issued_at: row.get(start + 5)?,
+        refresh_token: row.get::<_, String>(start + 6)?.into(),
+        client_id: Some(client_id.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(expires_at: i64, access_token: &str) -> SpotifyToken {
+        SpotifyToken {
+            access_token: access_token.into(),
+            token_type: "Bearer".to_string(),
+            scope: vec![SpotifyScope::Streaming, SpotifyScope::UserReadEmail],
+            expires_in: 3600,
+            expires_at: Some(expires_at),
+            issued_at: Some(expires_at - 3600),
+            refresh_token: "NgAagAHfVxDkSvCUm_SHo".into(),
+            client_id: Some("id".into()),
+        }
+    }
+
+    #[test]
+    fn test_sqlite_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tokens.db");
+        let store = SqliteTokenStore::open(&path, "id").unwrap();
+
+        store.save("expiring", &token(1_000, "a")).unwrap();
+        store.save("valid", &token(5_000, "b")).unwrap();
+        store.save("revoked", &token(1_000, "c")).unwrap();
+        store.mark_reauthorization_required("revoked").unwrap();
+
+        assert_eq!(store.load("valid").unwrap(), Some(token(5_000, "b")));
+        assert!(store.requires_reauthorization("revoked").unwrap());
+        assert_eq!(
+            store.expiring_before(2_000).unwrap(),
+            vec![("expiring".to_string(), token(1_000, "a"))]
+        );
+
+        // Tokens of other clients are kept apart, and the schema is only created once.
+        let other = SqliteTokenStore::open(&path, "other").unwrap();
+        assert_eq!(other.load("valid").unwrap(), None);
+        assert!(other.expiring_before(2_000).unwrap().is_empty());
+        assert_eq!(
+            other.save("valid", &token(5_000, "b")).unwrap_err().code(),
+            "client_mismatch"
+        );
+
+        // A database migrated by a newer version of the crate is not downgraded.
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        let error = SqliteTokenStore::open(&path, "id").unwrap_err();
+        assert_eq!(error.code(), "unsupported_format");
+        let version: usize = connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len() + 1);
+    }
+
+    #[test]
+    fn test_sqlite_store_replace() {
+        let clock = crate::ManualClock::new(Utc.timestamp_opt(1_000, 0).unwrap());
+        let store = SqliteTokenStore::open_in_memory("id")
+            .unwrap()
+            .with_clock(clock.clone());
+        store.save("user", &token(1_000, "a")).unwrap();
+
+        clock.advance(std::time::Duration::from_secs(60));
+        assert!(store
+            .replace("user", &token(1_000, "a"), &token(5_000, "b"))
+            .unwrap());
+        assert_eq!(
+            store.last_refresh("user").unwrap(),
+            Some((Utc.timestamp_opt(1_060, 0).unwrap(), 1))
+        );
+
+        // Saving a token is not counted as a refresh.
+        store.save("user", &token(5_000, "b")).unwrap();
+        assert_eq!(store.last_refresh("user").unwrap().unwrap().1, 1);
+
+        // The token has already been replaced, so the stale refresh is dropped.
+        assert!(!store
+            .replace("user", &token(1_000, "a"), &token(5_000, "c"))
+            .unwrap());
+        assert_eq!(store.load("user").unwrap(), Some(token(5_000, "b")));
+        assert!(store.expiring_before(2_000).unwrap().is_empty());
+
+        // Timestamps out of range are reported instead of panicking.
+        store
+            .connection
+            .lock()
+            .unwrap()
+            .execute("UPDATE spotify_tokens SET refreshed_at = ?1", [i64::MAX])
+            .unwrap();
+        assert_eq!(
+            store.last_refresh("user").unwrap_err().code(),
+            "database_error"
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! Generation of the ``state`` parameter of authorization requests.
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// The number of random bytes in a state by default.
+const DEFAULT_ENTROPY: usize = 32;
+/// The smallest number of random bytes in a state.
+const MIN_ENTROPY: usize = 16;
+
+/// Generates unguessable ``state`` values to protect authorization requests against CSRF.
+///
+/// States are encoded with the URL-safe base64 alphabet without padding, so they can be put in
+/// URLs and cookies as they are. Random bytes come from the operating system by default, a
+/// deterministic generator can be given with [`with_rng`](Self::with_rng) for tests.
+///
+/// # Example
+///
+/// ```
+/// # use spotify_oauth::StateGenerator;
+/// // 32 random bytes from the operating system.
+/// let state = StateGenerator::new().generate();
+/// # assert_eq!(state.len(), 43);
+///
+/// // A deterministic state for snapshot tests.
+/// let generator = StateGenerator::new()
+///     .with_entropy(16)
+///     .with_rng(rand::rngs::mock::StepRng::new(0, 1));
+/// assert_eq!(generator.generate(), "AAAAAAAAAAABAAAAAAAAAA");
+/// ```
+#[derive(Clone)]
+pub struct StateGenerator {
+    entropy: usize,
+    rng: Arc<Mutex<Box<dyn RngCore + Send>>>,
+}
+
+impl StateGenerator {
+    /// Create a new generator of 32 byte states using the operating system random number generator.
+    pub fn new() -> Self {
+        Self {
+            entropy: DEFAULT_ENTROPY,
+            rng: Arc::new(Mutex::new(Box::new(OsRng))),
+        }
+    }
+
+    /// Use the given number of random bytes for each state, at least 16.
+    pub fn with_entropy(mut self, bytes: usize) -> Self {
+        self.entropy = bytes.max(MIN_ENTROPY);
+        self
+    }
+
+    /// Use the given random number generator, which must be cryptographically secure outside of tests.
+    pub fn with_rng<R: RngCore + Send + 'static>(mut self, rng: R) -> Self {
+        self.rng = Arc::new(Mutex::new(Box::new(rng)));
+        self
+    }
+
+    /// Generate a new state.
+    pub fn generate(&self) -> String {
+        let mut bytes = vec![0u8; self.entropy];
+        self.rng
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .fill_bytes(&mut bytes);
+
+        base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD)
+    }
+}
+
+/// Implementation of Default for StateGenerator.
+///
+/// Equivalent to [`StateGenerator::new`].
+impl Default for StateGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for StateGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StateGenerator")
+            .field("entropy", &self.entropy)
+            .finish()
+    }
+}
+
+/// Generate a new state using the default [`StateGenerator`].
+///
+/// # Example
+///
+/// ```
+/// # use spotify_oauth::generate_state;
+/// let state = generate_state();
+/// # assert_ne!(state, generate_state());
+/// ```
+pub fn generate_state() -> String {
+    StateGenerator::new().generate()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::mock::StepRng;
+
+    #[test]
+    fn test_generate() {
+        let state = StateGenerator::new().generate();
+        assert_eq!(state.len(), 43);
+        assert!(state
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_'));
+        assert_ne!(state, StateGenerator::new().generate());
+
+        // Too little entropy is raised to the minimum.
+        let generator = StateGenerator::new().with_entropy(4);
+        assert_eq!(generator.generate().len(), 22);
+    }
+
+    #[test]
+    fn test_deterministic_rng() {
+        let generator = StateGenerator::new().with_rng(StepRng::new(u64::MAX, 0));
+        let copy = generator.clone();
+
+        assert_eq!(generator.generate(), "_".repeat(42) + "8");
+        assert_eq!(copy.generate(), generator.generate());
+    }
+}
@@ -0,0 +1,536 @@
+//! Persistence for Spotify Tokens.
+
+use fs2::FileExt;
+use snafu::ResultExt;
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::clock::{Clock, SystemClock};
+use crate::error::*;
+use crate::{SpotifyToken, TokenEnvelope};
+
+/// Numbers the temporary files of this process, so concurrent saves never share one.
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Storage backend for a Spotify Token.
+///
+/// Implementations are used by [`login`](crate::login) to persist the token once the
+/// authorization flow has completed, and can be used to load it again on the next run.
+pub trait TokenStore: Send + Sync {
+    /// Load the stored token, returning ``None`` if nothing has been stored yet.
+    fn load(&self) -> SpotifyResult<Option<SpotifyToken>>;
+
+    /// Store the given token, replacing any previously stored token.
+    fn save(&self, token: &SpotifyToken) -> SpotifyResult<()>;
+
+    /// Try to keep other processes from refreshing the stored token until the returned lock is
+    /// dropped, returning ``None`` without waiting while another process holds the lock.
+    ///
+    /// The [`TokenManager`](crate::TokenManager) takes the lock around every refresh, retrying
+    /// until it succeeds, and loads the token again once it holds it, so a token refreshed by
+    /// another process in the meantime is used instead of being refreshed twice. Stores which are
+    /// not shared between processes always succeed, which is the default.
+    fn try_lock(&self) -> SpotifyResult<Option<TokenLock>> {
+        Ok(Some(TokenLock { file: None }))
+    }
+}
+
+/// An exclusive lock on a stored token, released when dropped.
+///
+/// See [`TokenStore::try_lock`].
+#[derive(Debug)]
+pub struct TokenLock {
+    file: Option<File>,
+}
+
+impl Drop for TokenLock {
+    fn drop(&mut self) {
+        if let Some(ref file) = self.file {
+            // Called through the trait, as File::unlock only exists since Rust 1.89.
+            let _ = FileExt::unlock(file);
+        }
+    }
+}
+
+/// A Token Store which keeps the token as JSON in a file on disk.
+///
+/// The token is stored in a [`TokenEnvelope`]. Files holding a bare token, as written by older
+/// versions of the crate, are still loaded and are upgraded the next time the token is saved.
+///
+/// The token is written to a temporary file next to it and renamed into place, so readers never
+/// see a partially written token. On unix the file is only readable by its owner. Processes
+/// sharing the file lock a ``.lock`` file next to it while refreshing, see [`TokenStore::try_lock`].
+///
+/// A store created [`with_client_id`](Self::with_client_id) only loads and saves tokens issued
+/// to that client.
+///
+/// # Example
+///
+/// ```no_run
+/// # use spotify_oauth::{FileTokenStore, TokenStore};
+/// let store = FileTokenStore::new("token.json").with_client_id("00000000000");
+///
+/// // Load the token from a previous login if one exists.
+/// let token = store.load().unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct FileTokenStore {
+    path: PathBuf,
+    client_id: Option<String>,
+    clock: Arc<dyn Clock>,
+}
+
+impl FileTokenStore {
+    /// Create a new File Token Store backed by the file at the given path.
+    ///
+    /// The file is not touched until the token is loaded or saved.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            client_id: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Reject tokens issued to any other client than the given one, such as the client ID of the
+    /// configured [`SpotifyAuth`](crate::SpotifyAuth).
+    ///
+    /// Tokens stored without a client ID are loaded as belonging to this client.
+    pub fn with_client_id<S: Into<String>>(mut self, client_id: S) -> Self {
+        self.client_id = Some(client_id.into());
+        self
+    }
+
+    /// Date stored and refreshed tokens with the given clock, defaults to [`SystemClock`].
+    pub fn with_clock<K: Clock + 'static>(mut self, clock: K) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The path of the file backing this store.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Load the stored token with its metadata, returning ``None`` if nothing has been stored yet.
+    pub fn load_envelope(&self) -> SpotifyResult<Option<TokenEnvelope>> {
+        let buf = match fs::read_to_string(&self.path) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context(IoError),
+        };
+
+        TokenEnvelope::from_json(&buf, &*self.clock).map(Some)
+    }
+
+    /// Store the token with its metadata, replacing any previously stored token.
+    pub fn save_envelope(&self, envelope: &TokenEnvelope) -> SpotifyResult<()> {
+        let buf = envelope.to_json()?;
+        let temp = self.sibling(&format!(
+            ".{}.{}.tmp",
+            process::id(),
+            TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        let result = options
+            .open(&temp)
+            .and_then(|mut file| {
+                file.write_all(buf.as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&temp, &self.path));
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+
+        result.context(IoError)
+    }
+
+    /// The path of a file next to the token file, with the given suffix appended to its name.
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut name = self
+            .path
+            .file_name()
+            .map(|x| x.to_os_string())
+            .unwrap_or_default();
+        name.push(suffix);
+
+        self.path.with_file_name(name)
+    }
+}
+
+impl TokenStore for FileTokenStore {
+    fn load(&self) -> SpotifyResult<Option<SpotifyToken>> {
+        let envelope = match self.load_envelope()? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        let mut token = envelope.token;
+        if token.client_id.is_none() {
+            token.client_id = envelope.client_id.or_else(|| self.client_id.clone());
+        }
+        if let Some(ref client_id) = self.client_id {
+            token.verify_client(client_id)?;
+        }
+
+        Ok(Some(token))
+    }
+
+    /// Save the token, keeping the creation time of the stored envelope.
+    ///
+    /// Fails without touching the file if it holds an invalid token or was written in a newer
+    /// format.
+    fn save(&self, token: &SpotifyToken) -> SpotifyResult<()> {
+        if let Some(ref client_id) = self.client_id {
+            token.verify_client(client_id)?;
+        }
+
+        let client_id = token.client_id.clone().or_else(|| self.client_id.clone());
+        // Files which can't be read are left alone rather than overwritten in an older format.
+        let envelope = match self.load_envelope()? {
+            Some(x) => TokenEnvelope {
+                client_id: client_id.or(x.client_id.clone()),
+                ..x.refreshed(token.clone(), &*self.clock)
+            },
+            None => TokenEnvelope::new(client_id, token.clone(), &*self.clock),
+        };
+
+        self.save_envelope(&envelope)
+    }
+
+    /// Lock the ``.lock`` file next to the token file, unless another process holds it.
+    fn try_lock(&self) -> SpotifyResult<Option<TokenLock>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.sibling(".lock"))
+            .context(IoError)?;
+
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(Some(TokenLock { file: Some(file) })),
+            Err(ref e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                Ok(None)
+            }
+            Err(e) => Err(e).context(IoError),
+        }
+    }
+}
+
+/// Storage backend for the tokens of many users, keyed by user ID.
+///
+/// Used by the [`RefreshScheduler`](crate::RefreshScheduler) to find tokens which are about to
+/// expire, save refreshed tokens and flag users who have to authorize the application again.
+pub trait UserTokenStore: Send + Sync {
+    /// Load the token of the user, returning ``None`` if no token is stored.
+    fn load(&self, user_id: &str) -> SpotifyResult<Option<SpotifyToken>>;
+
+    /// Store the token of the user, replacing the previous token and clearing the
+    /// reauthorization flag.
+    fn save(&self, user_id: &str, token: &SpotifyToken) -> SpotifyResult<()>;
+
+    /// Store the refreshed token of the user only if the stored token is still ``current``,
+    /// returning whether it was stored. Nothing is stored if the user has no token.
+    ///
+    /// A token refreshed elsewhere in the meantime is kept, so a rotated refresh token is not
+    /// overwritten. The default implementation loads and saves the token, stores shared between
+    /// processes should compare and swap it atomically.
+    fn replace(
+        &self,
+        user_id: &str,
+        current: &SpotifyToken,
+        token: &SpotifyToken,
+    ) -> SpotifyResult<bool> {
+        match self.load(user_id)? {
+            Some(ref stored) if stored.access_token == current.access_token => {
+                self.save(user_id, token).map(|_| true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// The users and tokens which expire before the given unix timestamp, or whose expiry is
+    /// unknown. Users flagged for reauthorization are left out.
+    fn expiring_before(&self, timestamp: i64) -> SpotifyResult<Vec<(String, SpotifyToken)>>;
+
+    /// Flag the user as having to authorize the application again, as their refresh token was rejected.
+    fn mark_reauthorization_required(&self, user_id: &str) -> SpotifyResult<()>;
+
+    /// Whether the user has been flagged as having to authorize the application again.
+    fn requires_reauthorization(&self, user_id: &str) -> SpotifyResult<bool>;
+}
+
+/// A User Token Store keeping the tokens in memory.
+///
+/// # Example
+///
+/// ```
+/// # use spotify_oauth::{MemoryUserTokenStore, UserTokenStore};
+/// let store = MemoryUserTokenStore::new();
+/// assert_eq!(store.load("4kCdJ4Pkv3dD").unwrap(), None);
+/// ```
+#[derive(Debug, Default)]
+pub struct MemoryUserTokenStore {
+    tokens: Mutex<HashMap<String, (SpotifyToken, bool)>>,
+}
+
+impl MemoryUserTokenStore {
+    /// Create a new empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl UserTokenStore for MemoryUserTokenStore {
+    fn load(&self, user_id: &str) -> SpotifyResult<Option<SpotifyToken>> {
+        let tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(tokens.get(user_id).map(|(token, _)| token.clone()))
+    }
+
+    fn save(&self, user_id: &str, token: &SpotifyToken) -> SpotifyResult<()> {
+        let mut tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+        tokens.insert(user_id.to_string(), (token.clone(), false));
+        Ok(())
+    }
+
+    fn replace(
+        &self,
+        user_id: &str,
+        current: &SpotifyToken,
+        token: &SpotifyToken,
+    ) -> SpotifyResult<bool> {
+        let mut tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+        match tokens.get(user_id) {
+            Some((stored, _)) if stored.access_token == current.access_token => {
+                tokens.insert(user_id.to_string(), (token.clone(), false));
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn expiring_before(&self, timestamp: i64) -> SpotifyResult<Vec<(String, SpotifyToken)>> {
+        let tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+
+        Ok(tokens
+            .iter()
+            .filter(|(_, (token, reauthorize))| {
+                !reauthorize && token.estimated_expires_at().map_or(true, |x| x < timestamp)
+            })
+            .map(|(user_id, (token, _))| (user_id.clone(), token.clone()))
+            .collect())
+    }
+
+    fn mark_reauthorization_required(&self, user_id: &str) -> SpotifyResult<()> {
+        let mut tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((_, reauthorize)) = tokens.get_mut(user_id) {
+            *reauthorize = true;
+        }
+        Ok(())
+    }
+
+    fn requires_reauthorization(&self, user_id: &str) -> SpotifyResult<bool> {
+        let tokens = self.tokens.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(tokens.get(user_id).is_some_and(|(_, x)| *x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ManualClock, SpotifyScope};
+    use chrono::{TimeZone, Utc};
+    use std::time::Duration;
+
+    #[test]
+    fn test_file_store_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let clock = ManualClock::new(Utc.timestamp_opt(1_577_833_200, 0).unwrap());
+        let store = FileTokenStore::new(dir.path().join("token.json")).with_clock(clock.clone());
+
+        assert_eq!(store.load().unwrap(), None);
+
+        let token = SpotifyToken {
+            access_token: "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw".into(),
+            token_type: "Bearer".to_string(),
+            scope: vec![SpotifyScope::Streaming],
+            expires_in: 3600,
+            expires_at: Some(1_577_836_800),
+            issued_at: Some(1_577_833_200),
+            refresh_token: "NgAagAHfVxDkSvCUm_SHo".into(),
+            client_id: None,
+        };
+
+        store.save(&token).unwrap();
+        assert_eq!(store.load().unwrap(), Some(token.clone()));
+
+        // Saves are dated with the clock of the store.
+        clock.advance(Duration::from_secs(3600));
+        store.save(&token).unwrap();
+        let envelope = store.load_envelope().unwrap().unwrap();
+        assert_eq!(envelope.created_at, 1_577_833_200);
+        assert_eq!(envelope.refreshed_at, 1_577_836_800);
+
+        // Only the token and the lock file are left behind.
+        drop(store.try_lock().unwrap());
+        let mut files = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|x| x.unwrap().file_name())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, vec!["token.json", "token.json.lock"]);
+    }
+
+    #[test]
+    fn test_memory_user_store_expiring_before() {
+        let store = MemoryUserTokenStore::new();
+        let mut token = SpotifyToken {
+            access_token: "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw".into(),
+            token_type: "Bearer".to_string(),
+            scope: vec![],
+            expires_in: 3600,
+            expires_at: None,
+            issued_at: Some(1_577_833_200),
+            refresh_token: "NgAagAHfVxDkSvCUm_SHo".into(),
+            client_id: None,
+        };
+        store.save("issued", &token).unwrap();
+        token.issued_at = None;
+        store.save("unknown", &token).unwrap();
+
+        // Expiry is estimated from the issue time, whatever the current time.
+        let mut users = store
+            .expiring_before(1_577_836_801)
+            .unwrap()
+            .into_iter()
+            .map(|(user_id, _)| user_id)
+            .collect::<Vec<_>>();
+        users.sort();
+        assert_eq!(users, vec!["issued", "unknown"]);
+        assert_eq!(
+            store.expiring_before(1_577_836_800).unwrap()[0].0,
+            "unknown"
+        );
+    }
+
+    #[test]
+    fn test_file_store_client_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token.json");
+        let mut token = SpotifyToken {
+            access_token: "NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw".into(),
+            token_type: "Bearer".to_string(),
+            scope: vec![],
+            expires_in: 3600,
+            expires_at: Some(1_577_836_800),
+            issued_at: Some(1_577_833_200),
+            refresh_token: "NgAagAHfVxDkSvCUm_SHo".into(),
+            client_id: None,
+        };
+
+        // Tokens without a client ID are recorded as belonging to the configured client.
+        let staging = FileTokenStore::new(&path).with_client_id("staging");
+        staging.save(&token).unwrap();
+        assert_eq!(
+            staging
+                .load_envelope()
+                .unwrap()
+                .unwrap()
+                .client_id
+                .as_deref(),
+            Some("staging")
+        );
+
+        // Newer formats and corrupt files are not overwritten.
+        let newer = r#"{"version":2,"token":{}}"#;
+        fs::write(&path, newer).unwrap();
+        assert_eq!(
+            staging.save(&token).unwrap_err().code(),
+            "unsupported_format"
+        );
+        fs::write(&path, "{").unwrap();
+        assert_eq!(staging.save(&token).unwrap_err().code(), "invalid_json");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{");
+        fs::remove_file(&path).unwrap();
+        staging.save(&token).unwrap();
+
+        let production = FileTokenStore::new(&path).with_client_id("production");
+        assert_eq!(production.load().unwrap_err().code(), "client_mismatch");
+
+        token.client_id = Some("staging".into());
+        assert_eq!(
+            production.save(&token).unwrap_err().code(),
+            "client_mismatch"
+        );
+    }
+
+    #[test]
+    fn test_file_store_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileTokenStore::new(dir.path().join("token.json"));
+
+        let lock = store.try_lock().unwrap().unwrap();
+        let other = File::open(dir.path().join("token.json.lock")).unwrap();
+        assert!(other.try_lock_exclusive().is_err());
+        assert!(store.try_lock().unwrap().is_none());
+
+        drop(lock);
+        assert!(other.try_lock_exclusive().is_ok());
+        assert!(store.try_lock().unwrap().is_none());
+        FileExt::unlock(&other).unwrap();
+        assert!(store.try_lock().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_file_store_concurrent_saves() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileTokenStore::new(dir.path().join("token.json"));
+
+        let saves = (0..8)
+            .map(|i| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    let token = SpotifyToken {
+                        access_token: format!("NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw{}", i).into(),
+                        token_type: "Bearer".to_string(),
+                        scope: vec![],
+                        expires_in: 3600,
+                        expires_at: Some(1_577_836_800),
+                        issued_at: Some(1_577_833_200),
+                        refresh_token: "NgAagAHfVxDkSvCUm_SHo".into(),
+                        client_id: None,
+                    };
+                    store.save(&token)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // Every save gets its own temporary file, so none of them fail or mix up the token.
+        for save in saves {
+            save.join().unwrap().unwrap();
+        }
+        assert!(store.load().unwrap().is_some());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(store.path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
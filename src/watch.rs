@@ -0,0 +1,212 @@
+//! Reloading a token file when another process replaces it.
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use snafu::ResultExt;
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::error::*;
+use crate::manager::Subscribers;
+use crate::{FileTokenStore, SpotifyToken, TokenStore, TokenUpdates};
+
+/// Keeps the token of a [`FileTokenStore`] in memory, reloading it whenever the file changes.
+///
+/// Changes which do not contain a valid token, such as a partially written file, are ignored and
+/// the last valid token is kept. Every newly loaded token is sent to the streams returned by
+/// [`subscribe`](Self::subscribe). The file is watched until the watcher is dropped.
+///
+/// Requires the ``watch`` feature.
+///
+/// # Example
+///
+/// ```no_run
+/// # use spotify_oauth::FileTokenStore;
+/// let watcher = FileTokenStore::new("token.json").watch().unwrap();
+///
+/// // The latest token saved by a separate login, if any.
+/// let token = watcher.current();
+/// ```
+pub struct TokenWatcher {
+    shared: Arc<Shared>,
+    _watcher: RecommendedWatcher,
+}
+
+/// The state shared between the watcher and the thread handling file events.
+#[derive(Debug)]
+struct Shared {
+    store: FileTokenStore,
+    token: Mutex<Option<SpotifyToken>>,
+    subscribers: Subscribers,
+}
+
+impl Shared {
+    /// Load the token again, keeping the last token if the file is missing or invalid.
+    fn reload(&self) {
+        let token = match self.store.load() {
+            Ok(Some(x)) => x,
+            _ => return,
+        };
+
+        let mut current = self.token.lock().unwrap_or_else(|e| e.into_inner());
+        if current.as_ref() != Some(&token) {
+            self.subscribers.notify(&token);
+            *current = Some(token);
+        }
+    }
+}
+
+impl TokenWatcher {
+    /// The last valid token loaded from the file, ``None`` if there has been none yet.
+    pub fn current(&self) -> Option<SpotifyToken> {
+        self.shared
+            .token
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// A stream of every token loaded from the file from now on.
+    pub fn subscribe(&self) -> TokenUpdates {
+        self.shared.subscribers.subscribe()
+    }
+}
+
+impl fmt::Debug for TokenWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenWatcher")
+            .field("path", &self.shared.store.path())
+            .finish()
+    }
+}
+
+/// Watching for FileTokenStore.
+impl FileTokenStore {
+    /// Load the token and keep reloading it whenever the file changes.
+    ///
+    /// The directory containing the file is watched, so the file may be replaced by a rename as
+    /// done by [`save`](TokenStore::save). Fails if the file holds an invalid token.
+    pub fn watch(&self) -> SpotifyResult<TokenWatcher> {
+        let shared = Arc::new(Shared {
+            store: self.clone(),
+            token: Mutex::new(self.load()?),
+            subscribers: Subscribers::default(),
+        });
+
+        let name = self.path().file_name().map(|x| x.to_os_string());
+        let handler = shared.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let event = match event {
+                    Ok(x) => x,
+                    Err(_) => return,
+                };
+
+                let changed = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                    && event.paths.iter().any(|x| x.file_name() == name.as_deref());
+
+                if changed {
+                    handler.reload();
+                }
+            })
+            .map_err(watch_error)
+            .context(IoError)?;
+
+        let directory = match self.path().parent() {
+            Some(x) if !x.as_os_str().is_empty() => x,
+            _ => Path::new("."),
+        };
+        watcher
+            .watch(directory, RecursiveMode::NonRecursive)
+            .map_err(watch_error)
+            .context(IoError)?;
+
+        Ok(TokenWatcher {
+            shared,
+            _watcher: watcher,
+        })
+    }
+}
+
+/// Convert a failure to watch the file into an I/O error.
+fn watch_error(error: notify::Error) -> io::Error {
+    match error.kind {
+        notify::ErrorKind::Io(x) => x,
+        _ => io::Error::other(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::{FutureExt, StreamExt};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn token(access_token: &str) -> SpotifyToken {
+        SpotifyToken {
+            access_token: access_token.into(),
+            token_type: "Bearer".to_string(),
+            scope: vec![],
+            expires_in: 3600,
+            expires_at: Some(1_577_836_800),
+            issued_at: Some(1_577_833_200),
+            refresh_token: "NgAagAHfVxDkSvCUm_SHo".into(),
+            client_id: None,
+        }
+    }
+
+    /// Wait for the watcher to load the token with the given access token.
+    fn wait_for(watcher: &TokenWatcher, access_token: &str) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(10) {
+            if watcher
+                .current()
+                .is_some_and(|x| x.access_token.expose_secret() == access_token)
+            {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    #[test]
+    fn test_watch_reloads_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileTokenStore::new(dir.path().join("token.json"));
+        store
+            .save(&token("NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw"))
+            .unwrap();
+
+        let watcher = store.watch().unwrap();
+        let mut updates = watcher.subscribe();
+        assert_eq!(
+            watcher.current(),
+            Some(token("NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw"))
+        );
+
+        // A partially written file is ignored, once the watcher has had time to see it.
+        std::fs::write(store.path(), r#"{"access_token":"NgA6Zc"#).unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert!(updates.next().now_or_never().is_none());
+        assert_eq!(
+            watcher.current(),
+            Some(token("NgCXRKDjGUSKlfJODUjvnSUhcOMzYjw"))
+        );
+
+        // Saving over the invalid file fails, so replace it the way a separate login would.
+        store
+            .save_envelope(&crate::TokenEnvelope::new(
+                None,
+                token("NgA6ZcYIixn8bU"),
+                &crate::SystemClock,
+            ))
+            .unwrap();
+        assert!(wait_for(&watcher, "NgA6ZcYIixn8bU"));
+        assert_eq!(block_on(updates.next()), Some(token("NgA6ZcYIixn8bU")));
+    }
+}